@@ -84,6 +84,7 @@ in
           '';
           serviceConfig = {
             Type = "oneshot";
+            StateDirectory = "angrr";
           };
         };
       }
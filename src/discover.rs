@@ -0,0 +1,108 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use console::Term;
+
+use crate::{options::DiscoverOptions, scan_directories, store, RunContext};
+
+/// Find `result*` and `.direnv/*` links into the store which are not registered as GC roots
+pub fn discover(options: DiscoverOptions) -> anyhow::Result<()> {
+    let registered = registered_links(&options.directory)?;
+    let mut found = Vec::new();
+    for path in &options.path {
+        let path = std::path::absolute(path)
+            .with_context(|| format!("failed to get absolute path of {path:?}"))?;
+        walk(&options, &path, 1, &mut found)?;
+    }
+    found.sort();
+    let mut term = Term::stdout();
+    for (link, target) in found {
+        let canonical = link
+            .parent()
+            .and_then(|p| fs::canonicalize(p).ok())
+            .zip(link.file_name())
+            .map(|(p, n)| p.join(n));
+        if registered.contains(&link) || canonical.is_some_and(|c| registered.contains(&c)) {
+            log::debug!("{link:?} is already registered");
+            continue;
+        }
+        write!(
+            term,
+            "{:?} -> {target:?}",
+            term.style().underlined().apply_to(&link)
+        )?;
+        if options.register {
+            store::add_indirect_root(&link, &target)?;
+            write!(
+                term,
+                " {}",
+                term.style().green().bold().apply_to("registered")
+            )?;
+        }
+        writeln!(term)?;
+    }
+    Ok(())
+}
+
+/// Paths GC roots in `directories` and their subdirectories point to, found like `run` does
+fn registered_links(directories: &[PathBuf]) -> anyhow::Result<HashSet<PathBuf>> {
+    let mut links = HashSet::new();
+    let directories = RunContext::directories(directories)?;
+    scan_directories(&directories, None, |link_path| {
+        if let Ok(target) = fs::read_link(link_path) {
+            links.insert(target);
+        }
+        Ok(())
+    })?;
+    Ok(links)
+}
+
+fn walk(
+    options: &DiscoverOptions,
+    dir: &Path,
+    depth: usize,
+    found: &mut Vec<(PathBuf, PathBuf)>,
+) -> anyhow::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => {
+            log::debug!("skip {dir:?}: {e}");
+            return Ok(());
+        }
+    };
+    let in_direnv = dir.file_name().is_some_and(|n| n == ".direnv");
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("failed to read directory entry from directory {dir:?}"))?;
+        let path = entry.path();
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("failed to get file type of {path:?}"))?;
+        let name = entry.file_name();
+        if file_type.is_symlink() {
+            if !(in_direnv || name.as_encoded_bytes().starts_with(b"result")) {
+                continue;
+            }
+            let target = fs::read_link(&path)
+                .with_context(|| format!("failed to read symbolic link {path:?}"))?;
+            if options
+                .store
+                .iter()
+                .any(|s| store::store_path(s, &target).is_some())
+            {
+                found.push((path, target));
+            }
+        } else if file_type.is_dir()
+            && name != ".git"
+            && options.max_depth.is_none_or(|max| depth < max)
+        {
+            walk(options, &path, depth + 1, found)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+use std::{
+    ffi::CString,
+    fs,
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use console::Term;
+
+use crate::{
+    nix_config,
+    options::{DoctorOptions, RemoveMode, RunOptions},
+    store, RunContext,
+};
+
+/// Findings of the checks, printed as they are found
+struct Report {
+    term: Term,
+    warnings: usize,
+    errors: usize,
+}
+
+impl Report {
+    fn ok(&mut self, message: &str) -> anyhow::Result<()> {
+        let style = self.term.style().green().bold();
+        writeln!(self.term, "{} {message}", style.apply_to("ok"))?;
+        Ok(())
+    }
+
+    fn warn(&mut self, message: &str, advice: &str) -> anyhow::Result<()> {
+        self.warnings += 1;
+        let style = self.term.style().yellow().bold();
+        writeln!(self.term, "{} {message}", style.apply_to("warning"))?;
+        self.advice(advice)
+    }
+
+    fn error(&mut self, message: &str, advice: &str) -> anyhow::Result<()> {
+        self.errors += 1;
+        let style = self.term.style().red().bold();
+        writeln!(self.term, "{} {message}", style.apply_to("error"))?;
+        self.advice(advice)
+    }
+
+    fn advice(&mut self, advice: &str) -> anyhow::Result<()> {
+        self.term.write_line(&crate::add_indent(advice, 2))?;
+        Ok(())
+    }
+}
+
+pub fn doctor(options: DoctorOptions) -> anyhow::Result<()> {
+    let mut options = options.run;
+    let uid = uzers::get_current_uid();
+    if options.selection.nix_collect_garbage_compat {
+        RunContext::collect_garbage_compat(&mut options, uid);
+    }
+    let mut report = Report {
+        term: Term::stderr(),
+        warnings: 0,
+        errors: 0,
+    };
+
+    match store::version() {
+        Ok(version) => report.ok(&format!("nix-store is available: {version}"))?,
+        Err(e) => {
+            let message = format!("nix-store can not be run: {e:#}");
+            let needed = nix_store_features(&options);
+            if needed.is_empty() {
+                report.warn(
+                    &message,
+                    "the given options do not need it, only --min-closure-size, --delete-closure,\n\
+                     --keep-in-use, --free-until, --min-free-percent and --prune-derivation-roots do",
+                )?
+            } else {
+                report.error(
+                    &message,
+                    &format!("{} need nix-store in PATH", needed.join(", ")),
+                )?
+            }
+        }
+    }
+    for store in &options.selection.store {
+        match fs::canonicalize(store) {
+            Ok(canonical) if canonical.is_dir() => {
+                report.ok(&format!("store {store:?} exists at {canonical:?}"))?
+            }
+            Ok(_) => report.error(
+                &format!("store {store:?} is not a directory"),
+                "pass the store directory with --store, or set NIX_STORE_DIR",
+            )?,
+            Err(e) => report.error(
+                &format!("store {store:?} is not accessible: {e}"),
+                "every target would fail validation, pass the right directory with --store",
+            )?,
+        }
+    }
+
+    let directories = match RunContext::directories(&options.selection.directory) {
+        Ok(d) => d,
+        Err(e) => {
+            report.error(
+                &format!("failed to expand directories: {e:#}"),
+                "check the globs passed with --directory",
+            )?;
+            Vec::new()
+        }
+    };
+    if options.selection.root.is_empty()
+        && options.selection.roots_from.is_empty()
+        && directories.is_empty()
+    {
+        report.warn(
+            "no directory to scan for GC roots",
+            "globs passed with --directory match nothing",
+        )?;
+    }
+    for dir in &directories {
+        match fs::read_dir(dir) {
+            Ok(entries) => report.ok(&format!(
+                "directory {dir:?} is readable, {} entries",
+                entries.count()
+            ))?,
+            Err(e) => report.error(
+                &format!("directory {dir:?} is not readable: {e}"),
+                "run as a user allowed to read it, or drop it from --directory",
+            )?,
+        }
+        let writes_root = options.selection.remove != RemoveMode::Target;
+        if writes_root && !options.dry_run && !writable(dir) {
+            report.warn(
+                &format!("directory {dir:?} is not writable, GC roots in it can not be removed"),
+                "run as root or as the owner of the directory, or use --remove target",
+            )?;
+        }
+    }
+    for root in &options.selection.root {
+        if fs::symlink_metadata(root).is_err() {
+            report.warn(
+                &format!("GC root {root:?} given by --root does not exist"),
+                "it is skipped",
+            )?;
+        }
+    }
+
+    check_profiles(
+        &mut report,
+        uid,
+        options.selection.nix_collect_garbage_compat,
+    )?;
+    check_ownership(&mut report, &options, uid)?;
+
+    let state = nix_config::state_dir();
+    if state.is_dir() {
+        report.ok(&format!("Nix state directory {state:?} exists"))?;
+    } else {
+        report.warn(
+            &format!("Nix state directory {state:?} does not exist"),
+            "set NIX_STATE_DIR, or the store setting of nix.conf, for a relocated store",
+        )?;
+    }
+
+    let Report {
+        mut term,
+        warnings,
+        errors,
+    } = report;
+    writeln!(term, "{errors} error(s), {warnings} warning(s)")?;
+    if errors != 0 {
+        anyhow::bail!("{errors} problem(s) would make `run` fail or misbehave");
+    }
+    Ok(())
+}
+
+/// Profile links of the current user resolve into existing paths
+fn check_profiles(report: &mut Report, uid: u32, compat: bool) -> anyhow::Result<()> {
+    let dirs = nix_config::profile_dirs(uid);
+    if dirs.is_empty() && compat {
+        return report.warn(
+            "no profile directory found",
+            "--nix-collect-garbage-compat has no generation to handle",
+        );
+    }
+    for dir in dirs {
+        let links: Vec<PathBuf> = match fs::read_dir(&dir) {
+            Ok(entries) => entries
+                .filter_map(Result::ok)
+                .map(|e| e.path())
+                .filter(|p| p.is_symlink())
+                .collect(),
+            Err(e) => {
+                report.warn(
+                    &format!("profile directory {dir:?} is not readable: {e}"),
+                    "generations in it are neither listed nor removed",
+                )?;
+                continue;
+            }
+        };
+        let broken: Vec<&PathBuf> = links.iter().filter(|l| fs::metadata(l).is_err()).collect();
+        if broken.is_empty() {
+            report.ok(&format!(
+                "profile directory {dir:?}: {} links resolve",
+                links.len()
+            ))?;
+        } else {
+            report.warn(
+                &format!(
+                    "profile directory {dir:?}: {} of {} links do not resolve",
+                    broken.len(),
+                    links.len()
+                ),
+                &format!(
+                    "first one is {:?}, --clean-dangling removes them",
+                    broken[0]
+                ),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether the chosen `--owned-only` mode can work for the current user
+fn check_ownership(
+    report: &mut Report,
+    options: &crate::options::RunOptions,
+    uid: u32,
+) -> anyhow::Result<()> {
+    if options.selection.remove == RemoveMode::Root {
+        return Ok(());
+    }
+    if uid == 0 {
+        return report.ok("running as root, targets of all users can be removed");
+    }
+    if options.selection.owned_only {
+        report.ok("--owned-only: only targets owned by the current user are removed")
+    } else {
+        report.warn(
+            "--owned-only=false without root, targets of other users are removed only \
+             where the current user may write their directories",
+            "run as root, or keep --owned-only to skip them up front",
+        )
+    }
+}
+
+fn writable(path: &Path) -> bool {
+    let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `path` is nul terminated
+    unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 }
+}
+
+/// The given options which can not work without nix-store
+fn nix_store_features(options: &RunOptions) -> Vec<&'static str> {
+    [
+        (
+            options.selection.min_closure_size.is_some(),
+            "--min-closure-size",
+        ),
+        (options.delete_closure, "--delete-closure"),
+        (options.selection.keep_in_use, "--keep-in-use"),
+        (options.free_until.is_some(), "--free-until"),
+        (options.min_free_percent.is_some(), "--min-free-percent"),
+        (
+            options.selection.prune_derivation_roots,
+            "--prune-derivation-roots",
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(given, name)| given.then_some(name))
+    .collect()
+}
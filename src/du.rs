@@ -0,0 +1,95 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs,
+    io::Write,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use console::Term;
+
+use crate::{
+    options::{DuOptions, GroupBy},
+    store,
+};
+
+/// Usage of a group of GC roots
+#[derive(Debug, Default)]
+struct Usage {
+    roots: usize,
+    /// union of the closures of all roots in the group
+    closure: BTreeSet<PathBuf>,
+}
+
+/// Print the closure sizes of GC roots aggregated by group, largest first
+pub fn du(options: DuOptions) -> anyhow::Result<()> {
+    let mut roots = Vec::new();
+    for path in &options.directory {
+        collect_roots(path, &mut roots)?;
+    }
+    let mut groups: BTreeMap<String, Usage> = BTreeMap::new();
+    for link_path in roots {
+        let Some(target) = fs::read_link(&link_path).ok() else {
+            continue;
+        };
+        let store_path = fs::canonicalize(&target)
+            .ok()
+            .and_then(|p| options.store.iter().find_map(|s| store::store_path(s, &p)));
+        let Some(store_path) = store_path else {
+            log::debug!("target of {link_path:?} is not in store, skip");
+            continue;
+        };
+        let key = match options.group_by {
+            GroupBy::None => "total".to_owned(),
+            GroupBy::ParentDir => target
+                .parent()
+                .unwrap_or(&target)
+                .to_string_lossy()
+                .into_owned(),
+            GroupBy::PackageName => store::store_name(&store_path)
+                .map(store::package_name)
+                .map(|n| OsStr::from_bytes(n).to_string_lossy().into_owned())
+                .unwrap_or_default(),
+        };
+        let usage = groups.entry(key).or_default();
+        usage.roots += 1;
+        match store::requisites(&store_path) {
+            Ok(requisites) => usage.closure.extend(requisites),
+            Err(e) => log::warn!("failed to query closure of {store_path:?}, skip it: {e:#}"),
+        }
+    }
+    let mut rows = Vec::new();
+    for (key, usage) in groups {
+        let closure: Vec<_> = usage.closure.into_iter().collect();
+        rows.push((store::total_size(&closure)?, usage.roots, key));
+    }
+    rows.sort_by(|a, b| b.cmp(a));
+    let mut term = Term::stdout();
+    for (size, roots, key) in rows {
+        writeln!(
+            term,
+            "{:>10}  {:>5} roots  {key}",
+            term.style().bold().apply_to(store::format_size(size)),
+            roots
+        )?;
+    }
+    Ok(())
+}
+
+/// All entries in `dir` and its subdirectories, symbolic links to directories are not followed
+fn collect_roots(dir: &Path, roots: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    let entries = fs::read_dir(dir).with_context(|| format!("failed to open directory {dir:?}"))?;
+    for entry in entries {
+        let entry = entry
+            .with_context(|| format!("failed to read directory entry from directory {dir:?}"))?;
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            collect_roots(&path, roots)?;
+        } else {
+            roots.push(path);
+        }
+    }
+    Ok(())
+}
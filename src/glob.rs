@@ -0,0 +1,230 @@
+use std::{
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use regex::bytes::Regex;
+
+/// Compile a shell-style glob matching a whole path
+///
+/// `*` and `?` never match `/`, `**` matches across directories,
+/// and `**/` also matches no directory at all.
+/// `[...]`, `[!...]` and `{a,b}` are supported as in most shells.
+pub fn compile(glob: &str) -> anyhow::Result<Regex> {
+    let pattern = to_regex(glob);
+    log::trace!("glob {glob:?} compiled to regex {pattern:?}");
+    Regex::new(&pattern).with_context(|| format!("invalid glob {glob:?}"))
+}
+
+/// Whether `s` contains any glob syntax
+pub fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?', '[', '{'])
+}
+
+/// Expand a path whose components may be globs into the existing directories it matches
+///
+/// Every component is matched against the entries of the directories matched so far,
+/// the result is sorted.
+pub fn expand_directories(pattern: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dirs = vec![PathBuf::new()];
+    for component in pattern.components() {
+        let name = component.as_os_str();
+        let Some(glob) = name.to_str().filter(|s| is_glob(s)) else {
+            dirs.iter_mut().for_each(|d| d.push(name));
+            continue;
+        };
+        let regex = compile(glob)?;
+        let mut matched = Vec::new();
+        for dir in &dirs {
+            let read_dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            let entries = match fs::read_dir(read_dir) {
+                Ok(e) => e,
+                Err(e) => {
+                    log::debug!("skip {dir:?} while expanding {pattern:?}: {e}");
+                    continue;
+                }
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+                if regex.is_match(name.as_bytes()) {
+                    matched.push(dir.join(name));
+                }
+            }
+        }
+        dirs = matched;
+    }
+    dirs.retain(|d| d.is_dir());
+    dirs.sort();
+    Ok(dirs)
+}
+
+fn to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::from("(?s-u)^");
+    let mut brace_depth = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        match c {
+            '*' if chars.get(i) == Some(&'*') => {
+                i += 1;
+                if chars.get(i) == Some(&'/') {
+                    i += 1;
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => match class_end(&chars, i) {
+                Some(end) => {
+                    regex.push('[');
+                    let mut body = &chars[i..end];
+                    if let Some(('!', rest)) = body.split_first() {
+                        regex.push('^');
+                        body = rest;
+                    }
+                    for &c in body {
+                        if matches!(c, '\\' | '[' | ']' | '&' | '~') {
+                            regex.push('\\');
+                        }
+                        regex.push(c);
+                    }
+                    regex.push(']');
+                    i = end + 1;
+                }
+                // an unclosed `[` is literal like in shells
+                None => regex.push_str(r"\["),
+            },
+            '{' if has_closing_brace(&chars[i..]) => {
+                brace_depth += 1;
+                regex.push_str("(?:");
+            }
+            '}' if brace_depth > 0 => {
+                brace_depth -= 1;
+                regex.push(')');
+            }
+            ',' if brace_depth > 0 => regex.push('|'),
+            '\\' => {
+                if let Some(next) = chars.get(i) {
+                    i += 1;
+                    regex.push_str(&regex::escape(&next.to_string()));
+                }
+            }
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+/// Index of the `]` closing a class whose body starts at `start`,
+/// a `]` right after `[` or `[!` is part of the class
+fn class_end(chars: &[char], start: usize) -> Option<usize> {
+    let mut i = start;
+    if chars.get(i) == Some(&'!') {
+        i += 1;
+    }
+    if chars.get(i) == Some(&']') {
+        i += 1;
+    }
+    chars[i.min(chars.len())..]
+        .iter()
+        .position(|c| *c == ']')
+        .map(|p| i + p)
+}
+
+/// Whether a `{` followed by `rest` is closed
+fn has_closing_brace(rest: &[char]) -> bool {
+    let mut depth = 0;
+    let mut chars = rest.iter();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '{' => depth += 1,
+            '}' if depth == 0 => return true,
+            '}' => depth -= 1,
+            _ => (),
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(glob: &str, path: &str) -> bool {
+        compile(glob).unwrap().is_match(path.as_bytes())
+    }
+
+    #[test]
+    fn star_stays_in_component() {
+        assert!(matches("/a/*/c", "/a/b/c"));
+        assert!(!matches("/a/*/c", "/a/b/x/c"));
+        assert!(matches("/a/?", "/a/b"));
+        assert!(!matches("/a/?", "/a/bc"));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        assert!(matches("/a/**", "/a/b/c"));
+        assert!(matches("/a/**/c", "/a/c"));
+        assert!(matches("/a/**/c", "/a/b/d/c"));
+        assert!(!matches("/a/**/c", "/a/b/d"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(matches("[ab]x", "ax"));
+        assert!(!matches("[ab]x", "cx"));
+        assert!(matches("[!ab]x", "cx"));
+        assert!(!matches("[!ab]x", "ax"));
+        assert!(matches("[a-c]", "b"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[!]]", "a"));
+        assert!(matches("[[&]", "&"));
+    }
+
+    #[test]
+    fn braces() {
+        assert!(matches("{foo,bar}.nix", "foo.nix"));
+        assert!(matches("{foo,bar}.nix", "bar.nix"));
+        assert!(!matches("{foo,bar}.nix", "baz.nix"));
+        assert!(matches("{a,{b,c}}", "c"));
+        assert!(matches("a,b", "a,b"));
+    }
+
+    #[test]
+    fn unbalanced_is_literal() {
+        assert!(matches("{a,b", "{a,b"));
+        assert!(matches("a}", "a}"));
+        assert!(matches("[abc", "[abc"));
+        assert!(matches("x[", "x["));
+        assert!(matches("{a,[b}", "[b"));
+    }
+
+    #[test]
+    fn escapes() {
+        assert!(matches(r"\*", "*"));
+        assert!(!matches(r"\*", "a"));
+        assert!(matches("a.b", "a.b"));
+        assert!(!matches("a.b", "axb"));
+    }
+
+    #[test]
+    fn detects_globs() {
+        assert!(is_glob("/nix/var/nix/gcroots/per-user/*"));
+        assert!(!is_glob("/nix/var/nix/gcroots/auto"));
+    }
+}
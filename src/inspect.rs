@@ -0,0 +1,578 @@
+use std::{
+    collections::HashSet,
+    fmt::Write as _,
+    fs,
+    io::Write,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use console::Term;
+use humantime::format_duration;
+
+use crate::{
+    matches_any, nix_config,
+    options::{ExplainOptions, ListFormat, ListOptions, MatchOptions, StorePathKind},
+    store, KeepReason, Reason, RunContext, Verdict,
+};
+
+pub fn match_path(options: MatchOptions) -> anyhow::Result<()> {
+    let context = RunContext::new(options.selection.into())?;
+    let mut term = Term::stdout();
+    for link_path in find_roots(&context, &options.path)? {
+        mark_companion(&context, &link_path)?;
+        let verdict = context.verdict(&link_path)?;
+        print_verdict(&context, &mut term, &link_path, &verdict)?;
+    }
+    Ok(())
+}
+
+pub fn explain(options: ExplainOptions) -> anyhow::Result<()> {
+    let context = RunContext::new(options.selection.into())?;
+    let mut term = Term::stdout();
+    for link_path in find_roots(&context, &options.path)? {
+        mark_companion(&context, &link_path)?;
+        let verdict = context.verdict(&link_path)?;
+        print_verdict(&context, &mut term, &link_path, &verdict)?;
+        let trace = trace(&context, &link_path)?;
+        term.write_line(&crate::add_indent(&trace, 4))?;
+    }
+    Ok(())
+}
+
+/// Treat `link_path` as `run` would with `--prune-derivation-roots`,
+/// when it is the derivation root of another expired output root
+fn mark_companion(context: &RunContext, link_path: &Path) -> anyhow::Result<()> {
+    if !context.options.selection.prune_derivation_roots || !nix_config::keep_outputs() {
+        return Ok(());
+    }
+    let store_path = context
+        .resolve(link_path)
+        .ok()
+        .and_then(|p| context.store_path(&p));
+    let Some(store_path) = store_path.filter(|p| store::is_derivation(p)) else {
+        return Ok(());
+    };
+    let outputs: HashSet<_> = store::outputs(&store_path)?.into_iter().collect();
+    let mut expired_output = false;
+    context.scan_roots(|other| {
+        if expired_output || other == link_path {
+            return Ok(());
+        }
+        let is_output = context
+            .resolve(&other)
+            .ok()
+            .and_then(|p| context.store_path(&p))
+            .is_some_and(|p| outputs.contains(&p));
+        if is_output && matches!(context.verdict(&other)?, Verdict::Expired(_)) {
+            expired_output = true;
+        }
+        Ok(())
+    })?;
+    if expired_output {
+        context
+            .companions
+            .lock()
+            .unwrap()
+            .insert(link_path.to_owned());
+    }
+    Ok(())
+}
+
+/// Scanned GC roots that are `path` or point to it
+fn find_roots(context: &RunContext, path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let path = std::path::absolute(path)
+        .with_context(|| format!("failed to get absolute path of {path:?}"))?;
+    let mut roots = Vec::new();
+    context.scan_roots(|link_path| {
+        let is_root = std::path::absolute(&link_path).is_ok_and(|l| l == path);
+        if is_root || fs::read_link(&link_path).is_ok_and(|t| t == path) {
+            roots.push(link_path);
+        }
+        Ok(())
+    })?;
+    if roots.is_empty() {
+        anyhow::bail!("{path:?} is neither a scanned GC root nor the target of one");
+    }
+    Ok(roots)
+}
+
+/// Every check of the decision on `link_path`, one per line, in the order they are applied
+fn trace(context: &RunContext, link_path: &Path) -> anyhow::Result<String> {
+    let options = &context.options;
+    let mut lines = Vec::new();
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+    let directory = options
+        .selection
+        .directory
+        .iter()
+        .find(|d| link_path.starts_with(d));
+    lines.push(match directory {
+        _ if context.explicit_roots.is_some() => {
+            "found in: roots given by --root or --roots-from".to_owned()
+        }
+        Some(dir) => format!("found in: directory {dir:?}"),
+        None => "found in: unknown".to_owned(),
+    });
+    let generation = nix_config::generation(link_path);
+    if let Some((profile, n)) = &generation {
+        lines.push(format!("generation: {n} of profile {profile:?}"));
+    }
+    let target = match fs::read_link(link_path) {
+        Ok(t) => t,
+        Err(e) => {
+            lines.push(format!("target: unreadable, {e}"));
+            return Ok(lines.join("\n"));
+        }
+    };
+    let ago = |t: std::io::Result<std::time::SystemTime>| match t {
+        Ok(t) => context
+            .now
+            .duration_since(t)
+            .map(|d| format!("{} ago", format_seconds(d)))
+            .unwrap_or_else(|_| "in the future".to_owned()),
+        Err(e) => format!("unknown, {e}"),
+    };
+    if let Ok(metadata) = fs::symlink_metadata(link_path) {
+        lines.push(format!("GC root modified: {}", ago(metadata.modified())));
+    }
+
+    // path conditions, exclusions, pins and owners
+    if !context.selection.is_empty() {
+        let selected = matches_any(&context.selection, &target);
+        lines.push(format!("selected by --path-glob: {}", yes_no(selected)));
+    }
+    if !context.link_selection.is_empty() {
+        let selected = matches_any(&context.link_selection, link_path);
+        lines.push(format!(
+            "selected by --link-path-regex: {}",
+            yes_no(selected)
+        ));
+    }
+    let matched = |matcher: &crate::PathMatcher| {
+        yes_no(matcher.is_match(link_path) || matcher.is_match(&target))
+    };
+    lines.push(format!("excluded: {}", matched(&context.exclusions)));
+    lines.push(format!("pinned: {}", matched(&context.pins)));
+    if options.selection.no_keep_marker {
+        lines.push("keep marker: ignored by --no-keep-marker".to_owned());
+    } else {
+        match context.keep_marker(&target) {
+            Ok(Some(marker)) => lines.push(format!("keep marker: {marker:?}")),
+            Ok(None) => lines.push("keep marker: none".to_owned()),
+            Err(e) => lines.push(format!("keep marker: unknown, {e:#}")),
+        }
+    }
+    let metadata = match fs::symlink_metadata(&target) {
+        Ok(m) => m,
+        Err(e) => {
+            lines.push(format!("target metadata: unavailable, {e}"));
+            return Ok(lines.join("\n"));
+        }
+    };
+    let uid = metadata.uid();
+    let owner = owner_name(uid).unwrap_or_else(|| "unknown user".to_owned());
+    lines.push(format!("target owner: uid {uid} ({owner})"));
+    lines.push(format!("target modified: {}", ago(metadata.modified())));
+    lines.push(format!("target accessed: {}", ago(metadata.accessed())));
+    let resolved = context.resolve(&target);
+    match &resolved {
+        Ok(p) => lines.push(format!("resolved target: {p:?}")),
+        Err(e) => lines.push(format!("resolved target: unavailable, {e}")),
+    }
+    let store_path = resolved.as_ref().ok().and_then(|p| context.store_path(p));
+    match &store_path {
+        Some(store_path) => lines.push(format!("store path: {store_path:?}")),
+        None => lines.push("store path: none".to_owned()),
+    }
+    if let Some(kind) = options.selection.store_path_kind {
+        let is_drv = store_path.as_deref().map(store::is_derivation);
+        let matched = match kind {
+            StorePathKind::Drv => is_drv == Some(true),
+            StorePathKind::Output => is_drv == Some(false),
+        };
+        lines.push(format!(
+            "selected by --store-path-kind: {}",
+            yes_no(matched)
+        ));
+    }
+    if let Some(regex) = &context.store_name {
+        let name = store_path.as_deref().and_then(store::store_name);
+        let matched = name.is_some_and(|n| regex.is_match(n));
+        lines.push(format!(
+            "selected by --store-name-regex: {}",
+            yes_no(matched)
+        ));
+    }
+    if options.selection.owned_only {
+        lines.push(format!(
+            "owned by the current user: {}",
+            yes_no(uid == context.uid)
+        ));
+    }
+    if !context.owners.is_empty() || options.selection.owner_uid_range.is_some() {
+        let matched = context.owners.contains(&uid)
+            || options
+                .selection
+                .owner_uid_range
+                .as_ref()
+                .is_some_and(|r| r.contains(&uid));
+        lines.push(format!(
+            "selected by --owner or --owner-uid-range: {}",
+            yes_no(matched)
+        ));
+    }
+
+    // grouping and generations
+    if options.selection.keep_latest_n.is_some() {
+        lines.push(format!(
+            "latest of its group: {}",
+            yes_no(context.latest.contains(link_path))
+        ));
+    }
+    if options.selection.nix_collect_garbage_compat {
+        lines.push(format!(
+            "generation active now or at the retention cutoff: {}",
+            yes_no(context.active_generations.contains(link_path))
+        ));
+    }
+
+    // age
+    match context.reference_time(link_path, &target, &metadata) {
+        Ok(t) => lines.push(format!(
+            "age by --age-source {}: {}",
+            options
+                .selection
+                .age_source
+                .to_possible_value()
+                .map(|v| v.get_name().to_owned())
+                .unwrap_or_default(),
+            ago(Ok(t))
+        )),
+        Err(e) => lines.push(format!("age: unknown, {e:#}")),
+    }
+    if let Some(min_age) = options.selection.min_age {
+        lines.push(format!("minimum age: {}", format_seconds(min_age)));
+    }
+    lines.push(match context.period_overrides.get(&uid) {
+        Some(p) => format!("period: {} from --period-override", format_seconds(*p)),
+        None => format!("period: {}", format_seconds(options.selection.period)),
+    });
+    lines.push(format!("in denylist: {}", matched(&context.denylist)));
+    if options.selection.keep_latest_n.is_some() {
+        lines.push(format!(
+            "superseded: {}",
+            yes_no(context.superseded.contains(link_path))
+        ));
+    }
+    if options.selection.prune_derivation_roots {
+        lines.push(format!(
+            "derivation of another expired output root: {}",
+            yes_no(context.companions.lock().unwrap().contains(link_path))
+        ));
+    }
+
+    // closure and runtime usage
+    if let Some(min_size) = options.selection.min_closure_size {
+        let size = match context.validate(&target) {
+            Ok(true) if store::is_valid(&target).unwrap_or(false) => {
+                store::closure_size(&target).ok()
+            }
+            _ => None,
+        };
+        match size {
+            Some(size) => lines.push(format!(
+                "closure size: {size} bytes, --min-closure-size {min_size} bytes"
+            )),
+            None => lines.push("closure size: unknown".to_owned()),
+        }
+    }
+    match context.in_session(link_path, &target) {
+        Some((dir, pid)) => lines.push(format!("direnv session: {dir:?} by pid {pid}")),
+        None if !context.runtime.sessions.is_empty() => {
+            lines.push("direnv session: none".to_owned())
+        }
+        None => (),
+    }
+    if options.selection.keep_in_use {
+        match context.in_use(store_path.as_deref()) {
+            Some((path, pid)) => lines.push(format!("in use: {path:?} by pid {pid}")),
+            None => lines.push("in use: no".to_owned()),
+        }
+    }
+    let store = resolved
+        .ok()
+        .and_then(|p| context.store_of(&p).map(|(s, _)| s.to_owned()));
+    match store {
+        Some(store) => lines.push(format!("store: {store:?}")),
+        None => lines.push("store: outside every --store".to_owned()),
+    }
+    if generation.is_some() {
+        lines.push(format!(
+            "current generation of its profile: {}",
+            yes_no(nix_config::is_current_generation(link_path))
+        ));
+    }
+
+    // the final decision
+    if let Some(others) = context.shared_targets()?.get(&target) {
+        lines.push(format!(
+            "target shared with {} other GC roots",
+            others.len() - 1
+        ));
+    }
+    Ok(lines.join("\n"))
+}
+
+fn print_verdict(
+    context: &RunContext,
+    term: &mut Term,
+    link_path: &Path,
+    verdict: &Verdict,
+) -> anyhow::Result<()> {
+    let target = fs::read_link(link_path).ok();
+    writeln!(
+        term,
+        "GC root {:?} -> {:?}",
+        term.style().underlined().apply_to(link_path),
+        target.unwrap_or_default()
+    )?;
+    let line = match verdict {
+        Verdict::Expired(reason) => format_expired(context, term, reason),
+        Verdict::Keep(keep) => format_keep(term, keep),
+        Verdict::Dangling => format!(
+            "{}: target does not exist",
+            term.style().yellow().bold().apply_to("dangling")
+        ),
+        Verdict::Censored => format!(
+            "{}: target is not accessible to the current user",
+            term.style().yellow().bold().apply_to("censored")
+        ),
+    };
+    term.write_line(&crate::add_indent(&line, 2))?;
+    Ok(())
+}
+
+fn format_expired(context: &RunContext, term: &Term, reason: &Reason) -> String {
+    let mut text = format!(
+        "{}: {}",
+        term.style().red().bold().apply_to("expired"),
+        reason.format_with_style_no_target(term)
+    );
+    let store_path = context
+        .resolve(&reason.target)
+        .ok()
+        .and_then(|p| context.store_path(&p));
+    if let Some(store_path) = store_path {
+        // paths with referrers are still alive after removing the GC root
+        match store::referrers(&store_path) {
+            Ok(referrers) => text.push_str(&format!(
+                "\nstore path {store_path:?} is referred to by {} other store paths",
+                term.style().bold().apply_to(referrers.len())
+            )),
+            Err(e) => log::debug!("failed to query referrers of {store_path:?}: {e:#}"),
+        }
+    }
+    text
+}
+
+fn format_keep(term: &Term, keep: &KeepReason) -> String {
+    format!("{}: {keep}", term.style().green().bold().apply_to("kept"))
+}
+
+/// A scanned GC root as shown by `list`
+struct Entry {
+    link_path: PathBuf,
+    target: Option<PathBuf>,
+    generation: bool,
+    uid: Option<u32>,
+    age: Option<Duration>,
+    period: Duration,
+    verdict: &'static str,
+    detail: String,
+}
+
+pub fn list(options: ListOptions) -> anyhow::Result<()> {
+    let context = RunContext::new(options.selection.into())?;
+    let mut entries = Vec::new();
+    context.scan_roots(|link_path| {
+        let verdict = context.verdict(&link_path)?;
+        entries.push(entry(&context, link_path, &verdict));
+        Ok(())
+    })?;
+    let mut term = Term::stdout();
+    match options.format {
+        ListFormat::Table => print_table(&mut term, &entries)?,
+        ListFormat::Json => print_json(&mut term, &entries)?,
+    }
+    Ok(())
+}
+
+fn entry(context: &RunContext, link_path: PathBuf, verdict: &Verdict) -> Entry {
+    let target = fs::read_link(&link_path).ok();
+    let metadata = target.as_ref().and_then(|t| fs::symlink_metadata(t).ok());
+    let uid = metadata.as_ref().map(|m| m.uid());
+    let age = match (&target, &metadata) {
+        (Some(target), Some(metadata)) => context
+            .reference_time(&link_path, target, metadata)
+            .ok()
+            .and_then(|t| context.now.duration_since(t).ok()),
+        _ => None,
+    };
+    let period = *uid
+        .and_then(|uid| context.period_overrides.get(&uid))
+        .unwrap_or(&context.options.selection.period);
+    let (verdict, detail) = match verdict {
+        Verdict::Expired(reason) => (
+            "expired",
+            console::strip_ansi_codes(&reason.format_with_style_no_target(&context.term))
+                .replace('\n', ", "),
+        ),
+        Verdict::Keep(keep @ (KeepReason::Pinned | KeepReason::Marker { .. })) => {
+            ("pinned", keep.to_string())
+        }
+        Verdict::Keep(keep @ KeepReason::Excluded) => ("excluded", keep.to_string()),
+        Verdict::Keep(keep) => ("kept", keep.to_string()),
+        Verdict::Dangling => ("dangling", "target does not exist".to_owned()),
+        Verdict::Censored => ("censored", "target is not accessible".to_owned()),
+    };
+    Entry {
+        generation: nix_config::generation(&link_path).is_some(),
+        link_path,
+        target,
+        uid,
+        age,
+        period,
+        verdict,
+        detail,
+    }
+}
+
+fn owner_name(uid: u32) -> Option<String> {
+    uzers::get_user_by_uid(uid).map(|u| u.name().to_string_lossy().into_owned())
+}
+
+/// Durations in whole seconds, sub-second parts only add noise to a listing
+fn format_seconds(duration: Duration) -> String {
+    format_duration(Duration::from_secs(duration.as_secs())).to_string()
+}
+
+fn print_table(term: &mut Term, entries: &[Entry]) -> anyhow::Result<()> {
+    let rows: Vec<[String; 5]> = entries
+        .iter()
+        .map(|e| {
+            [
+                e.verdict.to_owned(),
+                e.uid
+                    .map(|uid| owner_name(uid).unwrap_or_else(|| uid.to_string()))
+                    .unwrap_or_else(|| "-".to_owned()),
+                e.age.map(format_seconds).unwrap_or_else(|| "-".to_owned()),
+                format_seconds(e.period),
+                if e.generation { "generation" } else { "root" }.to_owned(),
+            ]
+        })
+        .collect();
+    let header = ["VERDICT", "OWNER", "AGE", "PERIOD", "KIND"];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut line = String::new();
+    for (cell, width) in header.iter().zip(widths) {
+        write!(line, "{cell:width$}  ")?;
+    }
+    line.push_str("ROOT -> TARGET");
+    term.write_line(&term.style().bold().apply_to(line).to_string())?;
+    for (row, entry) in rows.iter().zip(entries) {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(widths) {
+            write!(line, "{cell:width$}  ")?;
+        }
+        write!(
+            line,
+            "{:?} -> {:?}\n{}",
+            entry.link_path,
+            entry.target.as_deref().unwrap_or(Path::new("")),
+            crate::add_indent(&entry.detail, 2)
+        )?;
+        term.write_line(&line)?;
+    }
+    Ok(())
+}
+
+fn print_json(term: &mut Term, entries: &[Entry]) -> anyhow::Result<()> {
+    let mut out = String::from("[");
+    for (i, entry) in entries.iter().enumerate() {
+        if i != 0 {
+            out.push(',');
+        }
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_owned());
+        write!(
+            out,
+            "\n  {{\"root\": {}, \"root_bytes\": {}, \"target\": {}, \"target_bytes\": {}, \
+             \"kind\": {}, \"uid\": {}, \"owner\": {}, \
+             \"age_secs\": {}, \"period_secs\": {}, \"verdict\": {}, \"detail\": {}}}",
+            json_string(&entry.link_path.to_string_lossy()),
+            json_bytes(&entry.link_path),
+            optional(
+                entry
+                    .target
+                    .as_ref()
+                    .map(|t| json_string(&t.to_string_lossy()))
+            ),
+            optional(entry.target.as_deref().map(json_bytes)),
+            json_string(if entry.generation {
+                "generation"
+            } else {
+                "root"
+            }),
+            optional(entry.uid.map(|uid| uid.to_string())),
+            optional(entry.uid.and_then(owner_name).map(|n| json_string(&n))),
+            optional(entry.age.map(|a| a.as_secs().to_string())),
+            entry.period.as_secs(),
+            json_string(entry.verdict),
+            json_string(&entry.detail),
+        )?;
+    }
+    if !entries.is_empty() {
+        out.push('\n');
+    }
+    out.push(']');
+    term.write_line(&out)?;
+    Ok(())
+}
+
+/// The exact bytes of a path as an array of numbers, strings may be lossy
+fn json_bytes(path: &Path) -> String {
+    let bytes: Vec<String> = path
+        .as_os_str()
+        .as_bytes()
+        .iter()
+        .map(u8::to_string)
+        .collect();
+    format!("[{}]", bytes.join(","))
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -1,17 +1,37 @@
+mod discover;
+mod doctor;
+mod du;
+mod glob;
+mod inspect;
+mod matcher;
+mod nix_config;
 mod options;
+mod proc;
+mod progress;
+mod self_test;
+mod status;
+mod store;
+mod touch;
+mod unlink;
 
 use std::{
+    cmp::Reverse,
+    collections::{BTreeSet, HashMap, HashSet},
     ffi::OsStr,
-    fmt::Debug,
-    fs::{self, File},
-    io::{self, sink, stdout, BufWriter, Write},
-    os::unix::{ffi::OsStrExt, fs::MetadataExt},
-    path::{Path, PathBuf},
+    fmt::{self, Debug},
+    fs::{self, Metadata, OpenOptions, Permissions},
+    io::{self, sink, stdout, BufWriter, Read, Write},
+    iter,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, OpenOptionsExt, PermissionsExt},
+    },
+    path::{Component, Path, PathBuf},
     sync::{
         atomic::{AtomicUsize, Ordering},
-        Mutex,
+        Mutex, OnceLock,
     },
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
@@ -19,7 +39,23 @@ use clap::{crate_name, CommandFactory, Parser};
 use console::Term;
 use dialoguer::Confirm;
 use humantime::format_duration;
-use options::{Interactive, Options, RunOptions};
+use matcher::PathMatcher;
+use options::{AgeSource, GroupBy, Interactive, Options, RemoveMode, RunOptions, StorePathKind};
+use progress::{Counts, Phase, Progress};
+use regex::bytes::Regex;
+use tempfile::TempPath;
+use unlink::Snapshot;
+
+/// Marker file pinning GC roots whose targets are below its directory
+const KEEP_MARKER: &str = ".angrr-keep";
+/// Only this many bytes of a keep marker are read, enough for any TTL
+const MAX_KEEP_MARKER_SIZE: u64 = 4096;
+/// Attempts of canonicalization before resolving lexically with `--lexical-fallback`
+const CANONICALIZE_ATTEMPTS: u32 = 3;
+/// Symbolic links followed at most when resolving lexically, like `MAXSYMLINKS` of Linux
+const MAX_SYMLINK_HOPS: usize = 40;
+/// Profile generation links like `profile-42-link`
+const GENERATION_REGEX: &str = "-[0-9]+-link$";
 
 fn main() -> anyhow::Result<()> {
     let carte_name = crate_name!();
@@ -36,11 +72,33 @@ fn main() -> anyhow::Result<()> {
 
     match options.command {
         options::Commands::Run(run_opts) => {
-            let context = RunContext::new(run_opts)?;
+            let dry_run = run_opts.dry_run;
+            let context = match RunContext::new(*run_opts) {
+                Ok(c) => c,
+                Err(e) => {
+                    status::record(&status::RunRecord::failed(dry_run, &e));
+                    return Err(e);
+                }
+            };
             log::trace!("context = {context:#?}");
-            context.run()?;
-            context.finish()
+            let result = context.run();
+            let mut record = context.run_record();
+            // the output is only flushed by `finish`, which may fail too
+            let result = result.and_then(|()| context.finish());
+            record.error = result.as_ref().err().map(|e| format!("{e:#}"));
+            status::record(&record);
+            result
         }
+        options::Commands::Match(match_opts) => inspect::match_path(*match_opts),
+        options::Commands::List(list_opts) => inspect::list(*list_opts),
+        options::Commands::Explain(explain_opts) => inspect::explain(*explain_opts),
+        options::Commands::Doctor(doctor_opts) => doctor::doctor(*doctor_opts),
+        options::Commands::Status(status_opts) => status::status(status_opts),
+        options::Commands::Discover(discover_opts) => discover::discover(discover_opts),
+        options::Commands::Du(du_opts) => du::du(du_opts),
+        options::Commands::Touch(touch_opts) => touch::touch(touch_opts),
+        options::Commands::ShellHook(hook_opts) => touch::shell_hook(hook_opts),
+        options::Commands::SelfTest(test_opts) => self_test::self_test(test_opts),
         options::Commands::Completion(gen_options) => {
             generate_shell_completions(gen_options, carte_name)
         }
@@ -54,6 +112,48 @@ struct RunContext {
     now: SystemTime,
     term: Term,
     output: Mutex<Output>,
+    exclusions: PathMatcher,
+    /// paths never removed, from `pins` files in state directories
+    pins: PathMatcher,
+    /// paths removed regardless of age, from `denylist` files in state directories
+    denylist: PathMatcher,
+    /// `--path-glob` patterns, every target is selected when empty
+    selection: Vec<Regex>,
+    /// `--link-path-regex` patterns, every GC root is selected when empty
+    link_selection: Vec<Regex>,
+    /// `--store-name-regex` pattern
+    store_name: Option<Regex>,
+    /// uids resolved from `--owner`
+    owners: Vec<u32>,
+    /// retention periods resolved from `--period-override`
+    period_overrides: HashMap<u32, Duration>,
+    progress: Mutex<Progress>,
+    /// canonical paths of `--store`, in the same order
+    canonical_stores: Vec<PathBuf>,
+    /// roots from `--root` and `--roots-from`, replacing the scan of directories
+    explicit_roots: Option<Vec<PathBuf>>,
+    /// roots kept by `--keep-latest-n` regardless of age
+    latest: HashSet<PathBuf>,
+    /// roots expired by `--expire-superseded` regardless of age
+    superseded: HashSet<PathBuf>,
+    /// generations kept by `--nix-collect-garbage-compat`
+    active_generations: HashSet<PathBuf>,
+    /// targets referenced by multiple GC roots, and these roots,
+    /// scanned when the first expired target is checked
+    shared_targets: OnceLock<HashMap<PathBuf, Vec<PathBuf>>>,
+    /// derivation roots of expired outputs, for `--prune-derivation-roots`
+    companions: Mutex<HashSet<PathBuf>>,
+    /// freed space estimated by `--free-until` and `--min-free-percent`
+    freed_estimate: Mutex<Option<u64>>,
+    /// store paths of removed GC roots, for `--delete-closure`
+    removed_store_paths: Mutex<Vec<PathBuf>>,
+    /// directories files were removed from, removed themselves once empty
+    removed_from: Mutex<BTreeSet<PathBuf>>,
+    /// directories of GC root links inside the scanned directories,
+    /// the only directories removed once empty
+    root_directories: Mutex<HashSet<PathBuf>>,
+    /// what running processes are using, for `--keep-in-use`
+    runtime: proc::Runtime,
     statistic: Statistics,
 }
 
@@ -68,12 +168,89 @@ enum Action {
 struct Reason {
     target: PathBuf,
     elapsed: Duration,
+    source: AgeSource,
+    /// why the retention period does not apply
+    forced: Option<Forced>,
+    /// the `--store` the target resolves into
+    store: Option<PathBuf>,
+    /// the file removed first as scanned, the target or the dangling GC root itself,
+    /// it is only removed if still unchanged
+    snapshot: Snapshot,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Forced {
+    Denylist,
+    Superseded,
+    Companion,
+    /// `--clean-dangling`, only the GC root itself is removed
+    Dangling,
+}
+
+/// A GC root passing the filters of [`RunContext::select`]
+struct Selected {
+    /// metadata of the target
+    metadata: Metadata,
+    store_path: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+enum Verdict {
+    Expired(Reason),
+    Keep(KeepReason),
+    Dangling,
+    /// the target is not accessible to the current user
+    Censored,
+}
+
+#[derive(Debug)]
+enum KeepReason {
+    NotSelected,
+    Latest,
+    CurrentGeneration,
+    ActiveGeneration,
+    Excluded,
+    Pinned,
+    Marker {
+        path: PathBuf,
+    },
+    NotOwned {
+        uid: u32,
+    },
+    OwnerMismatch {
+        uid: u32,
+    },
+    Young {
+        elapsed: Duration,
+        period: Duration,
+    },
+    BelowMinAge {
+        elapsed: Duration,
+        min_age: Duration,
+    },
+    UnknownClosure,
+    SmallClosure {
+        size: u64,
+    },
+    Referenced {
+        by: PathBuf,
+    },
+    InUse {
+        path: PathBuf,
+        pid: u32,
+    },
+    InSession {
+        dir: PathBuf,
+        pid: u32,
+    },
 }
 
 #[derive(Debug)]
 struct ToRemove<'c> {
     context: &'c RunContext,
     link_path: PathBuf,
+    /// other expired GC roots pointing to the same target
+    other_links: Vec<PathBuf>,
     reason: Reason,
 }
 
@@ -81,6 +258,10 @@ struct ToRemove<'c> {
 struct Statistics {
     traversed: Counter,
     candidate: Counter,
+    excluded: Counter,
+    pinned: Counter,
+    dangling: Counter,
+    censored: Counter,
     invalid: Counter,
     removed: Counter,
 }
@@ -89,6 +270,14 @@ struct Statistics {
 struct Output {
     writer: Box<dyn OutputWriter>,
     first_output: bool,
+    pending: Option<PendingOutput>,
+}
+
+/// Temporary file renamed to the output path on finish
+#[derive(Debug)]
+struct PendingOutput {
+    temp_path: TempPath,
+    path: PathBuf,
 }
 
 trait OutputWriter: Write + Debug {}
@@ -99,79 +288,574 @@ struct Counter(AtomicUsize);
 
 impl RunContext {
     fn new(options: RunOptions) -> anyhow::Result<Self> {
+        Self::with_state_dirs(options, &state_dirs())
+    }
+
+    /// Create a context reading the `pins` and `denylist` files from `state_dirs`
+    fn with_state_dirs(mut options: RunOptions, state_dirs: &[PathBuf]) -> anyhow::Result<Self> {
         let uid = uzers::get_current_uid();
+        if options.selection.nix_collect_garbage_compat {
+            Self::collect_garbage_compat(&mut options, uid);
+        }
+        options.selection.directory = Self::directories(&options.selection.directory)?;
         let now = SystemTime::now();
         let term = Term::stderr();
-        let output = Mutex::new(Output {
-            writer: Self::output_writer(&options)?,
-            first_output: true,
-        });
+        let output = Mutex::new(Self::output(&options)?);
+        let exclusions = Self::exclusions(&options)?;
+        let (pins, denylist) = Self::state_lists(state_dirs)?;
+        let selection = options
+            .selection
+            .path_glob
+            .iter()
+            .map(|g| glob::compile(g))
+            .collect::<anyhow::Result<_>>()?;
+        let link_selection = options
+            .selection
+            .link_path_regex
+            .iter()
+            .map(|r| {
+                Regex::new(r).with_context(|| format!("failed to compile regular expression {r:?}"))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let store_name = options
+            .selection
+            .store_name_regex
+            .as_ref()
+            .map(|r| {
+                Regex::new(r).with_context(|| format!("failed to compile regular expression {r:?}"))
+            })
+            .transpose()?;
+        let owners = options
+            .selection
+            .owner
+            .iter()
+            .map(|o| resolve_user(o))
+            .collect::<anyhow::Result<_>>()?;
+        let period_overrides = options
+            .selection
+            .period_override
+            .iter()
+            .map(|(user, period)| Ok((resolve_user(user)?, *period)))
+            .collect::<anyhow::Result<_>>()?;
+        let progress = Mutex::new(Progress::new(options.progress_fd)?);
+        let explicit_roots = Self::explicit_roots(&options)?;
+        let canonical_stores = options
+            .selection
+            .store
+            .iter()
+            .map(|s| fs::canonicalize(s).unwrap_or_else(|_| s.clone()))
+            .collect();
         let statistic = Default::default();
-        let context = Self {
+        let mut context = Self {
             options,
             uid,
             now,
             term,
             output,
+            exclusions,
+            pins,
+            denylist,
+            selection,
+            link_selection,
+            store_name,
+            owners,
+            period_overrides,
+            progress,
+            canonical_stores,
+            explicit_roots,
+            latest: HashSet::new(),
+            superseded: HashSet::new(),
+            active_generations: HashSet::new(),
+            shared_targets: OnceLock::new(),
+            companions: Default::default(),
+            freed_estimate: Default::default(),
+            removed_store_paths: Default::default(),
+            removed_from: Default::default(),
+            root_directories: Default::default(),
+            runtime: Default::default(),
             statistic,
         };
         log::debug!("options: {:#?}", context.options);
+        (context.latest, context.superseded) = context.group_roots()?;
+        if context.options.selection.nix_collect_garbage_compat {
+            context.active_generations = context.active_generations()?;
+        }
+        if context.options.selection.keep_in_use {
+            context.runtime = proc::scan(&context.options.selection.store)?;
+        }
         Ok(context)
     }
 
     fn run(&self) -> anyhow::Result<()> {
-        let mut waiting = Vec::new();
-
-        for path in &self.options.directory {
-            let directory =
-                fs::read_dir(path).with_context(|| format!("failed to open directory {path:?}"))?;
-            for entry in directory {
-                self.statistic.traversed.increase();
-                let link = entry.with_context(|| {
-                    format!("failed to read directory entry from directory {path:?}")
-                })?;
-                let link_path = link.path();
-                match self.check(&link_path)? {
-                    Some(reason) => {
-                        self.statistic.candidate.increase();
-                        let to_remove = ToRemove {
-                            context: self,
-                            link_path,
-                            reason,
-                        };
-                        match self.options.interactive {
-                            Interactive::Always => {
-                                to_remove.notify(Action::AboutToRemove, true)?;
-                                let yes = self.prompt()?;
-                                if yes {
-                                    to_remove.remove()?;
-                                } else {
-                                    to_remove.notify(Action::Ignored, true)?;
-                                }
-                            }
-                            Interactive::Once => {
-                                to_remove.notify(Action::AboutToRemove, true)?;
-                                waiting.push(to_remove);
-                            }
-                            Interactive::Never => {
-                                to_remove.remove()?;
-                            }
-                        }
+        let streaming = self.streaming();
+        let mut candidates = Vec::new();
+        // GC roots already handled together with another root of the same target
+        let mut grouped = HashSet::new();
+
+        self.scan_roots(|link_path| {
+            self.statistic.traversed.increase();
+            self.report_progress(Phase::Scan, Some(&link_path))?;
+            self.add_root_directory(&link_path);
+            if grouped.contains(&link_path) {
+                log::debug!("{link_path:?} is already handled with its target");
+                return Ok(());
+            }
+            match self.check(&link_path)? {
+                Some(reason) => {
+                    let to_remove = self.to_remove(link_path, reason)?;
+                    if !to_remove.is_dangling() {
+                        grouped.extend(to_remove.other_links.iter().cloned());
+                    }
+                    if streaming {
+                        to_remove.remove()?;
+                    } else {
+                        candidates.push(to_remove);
+                    }
+                }
+                None => log::trace!("keep {link_path:?}"),
+            }
+            Ok(())
+        })?;
+        if !streaming && !self.remove_candidates(candidates)? {
+            return self.report_progress(Phase::Done, None);
+        }
+        self.remove_empty_directories()?;
+        if self.options.clean_temproots {
+            self.clean_temproots()?;
+        }
+        self.delete_closures()?;
+
+        self.report_progress(Phase::Done, None)
+    }
+
+    /// Whether candidates can be removed as soon as they are found,
+    /// without prompts, limits or `--prune-derivation-roots` needing all of them first
+    fn streaming(&self) -> bool {
+        let options = &self.options;
+        options.interactive == Interactive::Never
+            && options.max_removals.is_none()
+            && options.max_removal_fraction.is_none()
+            && options.free_until.is_none()
+            && options.min_free_percent.is_none()
+            && !options.selection.prune_derivation_roots
+    }
+
+    /// Apply limits and prompts to all candidates of the run, then remove them,
+    /// returns `false` if the run is refused by a limit
+    fn remove_candidates<'c>(&'c self, mut candidates: Vec<ToRemove<'c>>) -> anyhow::Result<bool> {
+        if self.options.selection.prune_derivation_roots {
+            self.add_companions(&mut candidates)?;
+        }
+        self.limit_to_free_space(&mut candidates)?;
+        if !self.check_removal_limits(&candidates)? {
+            return Ok(false);
+        }
+        match self.options.interactive {
+            Interactive::Always => {
+                for to_remove in &candidates {
+                    to_remove.notify(Action::AboutToRemove, true)?;
+                    if self.prompt()? {
+                        to_remove.remove()?;
+                    } else {
+                        to_remove.notify(Action::Ignored, true)?;
+                    }
+                }
+            }
+            Interactive::Once => {
+                for to_remove in &candidates {
+                    to_remove.notify(Action::AboutToRemove, true)?;
+                }
+                if !candidates.is_empty() && self.prompt()? {
+                    for to_remove in &candidates {
+                        to_remove.remove()?;
+                    }
+                }
+            }
+            Interactive::Never => {
+                for to_remove in &candidates {
+                    to_remove.remove()?;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn to_remove(&self, link_path: PathBuf, reason: Reason) -> anyhow::Result<ToRemove<'_>> {
+        // other dangling roots of the same target are removed on their own
+        let other_links: Vec<_> = match reason.forced {
+            Some(Forced::Dangling) => Vec::new(),
+            _ => self
+                .shared_targets()?
+                .get(&reason.target)
+                .into_iter()
+                .flatten()
+                .filter(|l| **l != link_path)
+                .cloned()
+                .collect(),
+        };
+        self.statistic.candidate.add(1 + other_links.len());
+        Ok(ToRemove {
+            context: self,
+            link_path,
+            other_links,
+            reason,
+        })
+    }
+
+    /// Add GC roots of the derivations of expired outputs, which keep the outputs alive
+    /// with keep-outputs
+    ///
+    /// Output roots are never forced, with keep-derivations they keep an expired
+    /// derivation alive, which is only reported.
+    fn add_companions<'c>(&'c self, candidates: &mut Vec<ToRemove<'c>>) -> anyhow::Result<()> {
+        let keep_outputs = nix_config::keep_outputs();
+        let keep_derivations = nix_config::keep_derivations();
+        if !keep_outputs && !keep_derivations {
+            return Ok(());
+        }
+        let mut roots: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+        self.scan_roots(|link_path| {
+            if let Some(store_path) = self
+                .resolve(&link_path)
+                .ok()
+                .and_then(|p| self.store_path(&p))
+            {
+                roots.entry(store_path).or_default().push(link_path);
+            }
+            Ok(())
+        })?;
+        let mut handled = HashSet::new();
+        let mut related = Vec::new();
+        for to_remove in candidates.iter() {
+            handled.insert(to_remove.link_path.clone());
+            handled.extend(to_remove.other_links.iter().cloned());
+            let store_path = match self
+                .resolve(&to_remove.reason.target)
+                .ok()
+                .and_then(|p| self.store_path(&p))
+            {
+                Some(p) => p,
+                None => continue,
+            };
+            if !store::is_derivation(&store_path) {
+                if keep_outputs {
+                    related.extend(store::deriver(&store_path)?);
+                }
+                continue;
+            }
+            if keep_derivations {
+                let kept_by = store::outputs(&store_path)?
+                    .into_iter()
+                    .filter_map(|p| roots.get(&p))
+                    .flatten()
+                    .find(|l| !candidates.iter().any(|c| c.link_path == **l));
+                if let Some(kept_by) = kept_by {
+                    log::info!(
+                        "{:?} is kept alive by output root {kept_by:?} with keep-derivations",
+                        to_remove.link_path
+                    );
+                }
+            }
+        }
+        let companions: BTreeSet<PathBuf> = related
+            .iter()
+            .filter_map(|p| roots.get(p))
+            .flatten()
+            .filter(|l| !handled.contains(*l))
+            .cloned()
+            .collect();
+        self.companions
+            .lock()
+            .unwrap()
+            .extend(companions.iter().cloned());
+        for link_path in companions {
+            if handled.contains(&link_path) {
+                continue;
+            }
+            match self.verdict(&link_path)? {
+                Verdict::Expired(reason) => {
+                    let to_remove = self.to_remove(link_path, reason)?;
+                    handled.extend(to_remove.other_links.iter().cloned());
+                    candidates.push(to_remove);
+                }
+                Verdict::Keep(keep) => log::debug!("keep companion {link_path:?}: {keep}"),
+                _ => log::debug!("skip companion {link_path:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Remember the directory of `link_path` if it is a directory of links registered as a root,
+    /// neither a scanned directory nor one of the directories of Nix itself
+    fn add_root_directory(&self, link_path: &Path) {
+        let Some(dir) = link_path.parent() else {
+            return;
+        };
+        let inside_scanned = self
+            .options
+            .selection
+            .directory
+            .iter()
+            .any(|d| dir != d && dir.starts_with(d));
+        if inside_scanned && !nix_config::is_nix_directory(dir) {
+            self.root_directories.lock().unwrap().insert(dir.to_owned());
+        }
+    }
+
+    /// Remove directories of GC root links that became empty
+    fn remove_empty_directories(&self) -> anyhow::Result<()> {
+        let removed_from = self.removed_from.lock().unwrap();
+        let root_directories = self.root_directories.lock().unwrap();
+        // deepest first, so parents emptied by removing their children are removed too
+        for dir in removed_from.iter().rev() {
+            for dir in dir.ancestors() {
+                if !root_directories.contains(dir) {
+                    break;
+                }
+                match fs::remove_dir(dir) {
+                    Ok(()) => log::info!("removed empty directory {dir:?}"),
+                    Err(e) if e.kind() == io::ErrorKind::DirectoryNotEmpty => break,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => {
+                        return Err(e)
+                            .with_context(|| format!("failed to remove directory {dir:?}"))
                     }
-                    None => log::trace!("keep {link_path:?}"),
                 }
             }
         }
+        Ok(())
+    }
 
-        if !waiting.is_empty() && self.prompt()? {
-            for to_remove in &waiting {
-                to_remove.remove()?;
+    /// Remove temporary roots of dead processes in the Nix state directory
+    fn clean_temproots(&self) -> anyhow::Result<()> {
+        let dir = nix_config::state_dir().join("temproots");
+        let entries = match fs::read_dir(&dir) {
+            Ok(e) => e,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            e => e.with_context(|| format!("failed to open directory {dir:?}"))?,
+        };
+        let mut term = self.term.clone();
+        for entry in entries {
+            let path = entry
+                .with_context(|| format!("failed to read directory entry from directory {dir:?}"))?
+                .path();
+            let removed = proc::remove_stale_temproots(&path, self.options.dry_run)
+                .with_context(|| format!("failed to remove stale temporary roots {path:?}"))?;
+            if removed {
+                writeln!(
+                    term,
+                    "{} stale temporary roots {path:?}",
+                    Action::Remove.format_with_style(&term)
+                )?;
+            } else {
+                log::debug!("temporary roots {path:?} are still in use");
             }
         }
+        Ok(())
+    }
 
+    /// Delete the closures of removed GC roots from the store, skipping paths still alive
+    fn delete_closures(&self) -> anyhow::Result<()> {
+        let roots = self.removed_store_paths.lock().unwrap();
+        if roots.is_empty() {
+            return Ok(());
+        }
+        let mut paths = BTreeSet::new();
+        for root in roots.iter() {
+            match store::requisites(root) {
+                Ok(requisites) => paths.extend(requisites),
+                Err(e) => log::warn!("failed to query closure of {root:?}, skip it: {e:#}"),
+            }
+        }
+        let mut term = self.term.clone();
+        if self.options.dry_run {
+            writeln!(
+                term,
+                "{} up to {} store paths in closures of removed GC roots",
+                term.style().bold().green().apply_to("Delete"),
+                paths.len()
+            )?;
+            return Ok(());
+        }
+        let summary = store::delete_skip_live(&paths.into_iter().collect::<Vec<_>>())?;
+        writeln!(
+            term,
+            "{} closures of removed GC roots, {summary}",
+            term.style().bold().green().apply_to("Delete")
+        )?;
         Ok(())
     }
 
+    /// Keep only the oldest candidates needed for `--free-until` and `--min-free-percent`
+    ///
+    /// The freed space is estimated with referrers of the closures, see [`store::FreedSpace`].
+    fn limit_to_free_space(&self, candidates: &mut Vec<ToRemove>) -> anyhow::Result<()> {
+        let (free_until, min_free_percent) =
+            (self.options.free_until, self.options.min_free_percent);
+        if free_until.is_none() && min_free_percent.is_none() {
+            return Ok(());
+        }
+        let store = &self.options.selection.store[0];
+        let (free, total) = store::filesystem_space(store)
+            .with_context(|| format!("failed to get free space of store {store:?}"))?;
+        let target = free_until
+            .unwrap_or_default()
+            .max(total / 100 * u64::from(min_free_percent.unwrap_or_default()));
+        log::info!(
+            "{} free in store filesystem, target {}",
+            store::format_size(free),
+            store::format_size(target)
+        );
+        let needed = target.saturating_sub(free);
+        if needed == 0 {
+            candidates.clear();
+            return Ok(());
+        }
+        if candidates.is_empty() {
+            return Ok(());
+        }
+        candidates.sort_by_key(|c| Reverse(c.reason.elapsed));
+        let mut alive = HashSet::new();
+        let removed: HashSet<_> = candidates
+            .iter()
+            .flat_map(|c| iter::once(&c.link_path).chain(&c.other_links))
+            .collect();
+        self.scan_roots(|link_path| {
+            if !removed.contains(&link_path) {
+                let store_path = self
+                    .resolve(&link_path)
+                    .ok()
+                    .and_then(|p| self.store_path(&p));
+                alive.extend(store_path);
+            }
+            Ok(())
+        })?;
+        let closures: Vec<_> = candidates
+            .iter()
+            .map(|candidate| {
+                let store_path = self
+                    .resolve(&candidate.reason.target)
+                    .ok()
+                    .and_then(|p| self.store_path(&p))?;
+                match store::requisites(&store_path) {
+                    Ok(closure) => Some(closure),
+                    Err(e) => {
+                        log::warn!("failed to query closure of {store_path:?}, skip it: {e:#}");
+                        None
+                    }
+                }
+            })
+            .collect();
+        let estimate = |count: usize| {
+            let mut freed = store::FreedSpace::default();
+            for closure in closures[..count].iter().flatten() {
+                freed.add(closure);
+            }
+            match freed.estimate(&alive) {
+                Ok(estimate) => Some(estimate),
+                Err(e) => {
+                    log::warn!("failed to estimate space freed by {count} GC roots: {e:#}");
+                    None
+                }
+            }
+        };
+        // the fewest oldest candidates freeing enough, found by doubling and then bisecting,
+        // so only a logarithmic number of estimates is needed
+        let enough = |estimate: Option<u64>| estimate.is_some_and(|e| e >= needed);
+        let (mut low, mut high) = (0, 1);
+        let mut high_estimate = estimate(high);
+        while !enough(high_estimate) && high < candidates.len() {
+            low = high;
+            high = (high * 2).min(candidates.len());
+            high_estimate = estimate(high);
+        }
+        while enough(high_estimate) && high - low > 1 {
+            let middle = low + (high - low) / 2;
+            let middle_estimate = estimate(middle);
+            if enough(middle_estimate) {
+                (high, high_estimate) = (middle, middle_estimate);
+            } else {
+                low = middle;
+            }
+        }
+        let keep = high;
+        let estimate = high_estimate.unwrap_or_default();
+        log::info!(
+            "removing {keep} GC roots frees about {}",
+            store::format_size(estimate)
+        );
+        *self.freed_estimate.lock().unwrap() = high_estimate;
+        for candidate in candidates.drain(keep..) {
+            log::debug!("keep {:?}: free space target met", candidate.link_path);
+        }
+        Ok(())
+    }
+
+    /// Check `--max-removals` and `--max-removal-fraction` before removing anything
+    ///
+    /// Exceeding a limit aborts the run, or asks for confirmation when interactive.
+    fn check_removal_limits(&self, candidates: &[ToRemove]) -> anyhow::Result<bool> {
+        let removals: usize = candidates.iter().map(|c| 1 + c.other_links.len()).sum();
+        let traversed = self.statistic.traversed.value();
+        let mut exceeded = Vec::new();
+        if let Some(max) = self.options.max_removals {
+            if removals > max {
+                exceeded.push(format!("more than {max}"));
+            }
+        }
+        if let Some(fraction) = self.options.max_removal_fraction {
+            if removals as f64 > fraction * traversed as f64 {
+                exceeded.push(format!(
+                    "more than {}% of {traversed} traversed GC roots",
+                    fraction * 100.0
+                ));
+            }
+        }
+        if exceeded.is_empty() {
+            return Ok(true);
+        }
+        let message = format!(
+            "{removals} GC roots are about to be removed, {}",
+            exceeded.join(" and ")
+        );
+        if self.options.dry_run {
+            log::warn!("{message}");
+            Ok(true)
+        } else if self.options.interactive == Interactive::Never {
+            anyhow::bail!(
+                "{message}, refuse to remove any of them, raise the limits or run interactively to confirm"
+            )
+        } else {
+            let mut term = self.term.clone();
+            writeln!(
+                term,
+                "{}, {message}",
+                term.style().bold().red().apply_to("Removal limit exceeded")
+            )?;
+            self.prompt()
+        }
+    }
+
+    /// A summary of the run for `status`, without its error
+    fn run_record(&self) -> status::RunRecord {
+        let statistic = &self.statistic;
+        status::RunRecord {
+            time: self
+                .now
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            dry_run: self.options.dry_run,
+            error: None,
+            traversed: statistic.traversed.value(),
+            candidate: statistic.candidate.value(),
+            removed: statistic.removed.value(),
+            dangling: statistic.dangling.value(),
+            censored: statistic.censored.value(),
+            freed: *self.freed_estimate.lock().unwrap(),
+        }
+    }
+
     fn finish(mut self) -> anyhow::Result<()> {
         if !self.options.no_statistic {
             writeln!(
@@ -182,52 +866,640 @@ impl RunContext {
             self.term
                 .write_line(&self.statistic.format_with_style(&self.term))?;
         }
-        let mut output = self.output.lock().unwrap();
-        output.writer.flush().context("failed to flush output")?;
-        Ok(())
+        self.output.into_inner().unwrap().finish()
+    }
+
+    fn scan_roots<F>(&self, mut f: F) -> anyhow::Result<()>
+    where
+        F: FnMut(PathBuf) -> anyhow::Result<()>,
+    {
+        if let Some(roots) = &self.explicit_roots {
+            for root in roots {
+                f(root.clone())?;
+            }
+            return Ok(());
+        }
+        scan_directories(
+            &self.options.selection.directory,
+            self.options.selection.max_depth,
+            f,
+        )
+    }
+
+    /// Collect the newest `--keep-latest-n` roots of every group,
+    /// and the other grouped roots if `--expire-superseded` is given,
+    /// only roots passing [`Self::select`] are grouped
+    fn group_roots(&self) -> anyhow::Result<(HashSet<PathBuf>, HashSet<PathBuf>)> {
+        let n = match self.options.selection.keep_latest_n {
+            Some(n) => n,
+            None => return Ok(Default::default()),
+        };
+        let mut groups: HashMap<Option<PathBuf>, Vec<(SystemTime, PathBuf)>> = HashMap::new();
+        self.scan_roots(|link_path| {
+            let target = match fs::read_link(&link_path) {
+                Ok(t) => t,
+                Err(_) => return Ok(()),
+            };
+            // roots other filters keep or skip never take a slot of the latest ones
+            let metadata = match self.select(&link_path, &target)? {
+                Ok(Selected { metadata, .. }) => metadata,
+                Err(_) => return Ok(()),
+            };
+            let mtime = match self.reference_time(&link_path, &target, &metadata) {
+                Ok(t) => t,
+                Err(_) => return Ok(()),
+            };
+            let key = match self.options.selection.group_by {
+                GroupBy::None => None,
+                GroupBy::ParentDir => target.parent().map(Path::to_path_buf),
+                GroupBy::PackageName => {
+                    let package = self
+                        .resolve(&target)
+                        .ok()
+                        .and_then(|p| self.store_path(&p))
+                        .and_then(|p| {
+                            store::store_name(&p)
+                                .map(store::package_name)
+                                .map(|n| PathBuf::from(OsStr::from_bytes(n)))
+                        });
+                    if package.is_none() {
+                        // roots outside store are not grouped
+                        return Ok(());
+                    }
+                    package
+                }
+            };
+            groups.entry(key).or_default().push((mtime, link_path));
+            Ok(())
+        })?;
+        let mut latest = HashSet::new();
+        let mut superseded = HashSet::new();
+        for (key, mut roots) in groups {
+            roots.sort_by_key(|(mtime, _)| Reverse(*mtime));
+            for (i, (_, link_path)) in roots.into_iter().enumerate() {
+                if i < n {
+                    log::trace!("{link_path:?} is one of the latest {n} roots of group {key:?}");
+                    latest.insert(link_path);
+                } else if self.options.selection.expire_superseded {
+                    superseded.insert(link_path);
+                }
+            }
+        }
+        Ok((latest, superseded))
+    }
+
+    /// Targets referenced by more than one GC root, scanned on first use
+    fn shared_targets(&self) -> anyhow::Result<&HashMap<PathBuf, Vec<PathBuf>>> {
+        if let Some(targets) = self.shared_targets.get() {
+            return Ok(targets);
+        }
+        let targets = self.scan_shared_targets()?;
+        Ok(self.shared_targets.get_or_init(|| targets))
+    }
+
+    /// Collect targets referenced by more than one GC root,
+    /// only needed when targets are removed
+    fn scan_shared_targets(&self) -> anyhow::Result<HashMap<PathBuf, Vec<PathBuf>>> {
+        let mut targets: HashMap<PathBuf, Vec<PathBuf>> = HashMap::new();
+        if self.options.selection.remove == RemoveMode::Root {
+            return Ok(targets);
+        }
+        self.scan_roots(|link_path| {
+            if let Ok(target) = fs::read_link(&link_path) {
+                targets.entry(target).or_default().push(link_path);
+            }
+            Ok(())
+        })?;
+        targets.retain(|_, links| links.len() > 1);
+        Ok(targets)
+    }
+
+    fn report_progress(&self, phase: Phase, item: Option<&Path>) -> anyhow::Result<()> {
+        let counts = Counts {
+            traversed: self.statistic.traversed.value(),
+            candidate: self.statistic.candidate.value(),
+            removed: self.statistic.removed.value(),
+        };
+        self.progress.lock().unwrap().report(phase, counts, item)
     }
 
     fn check<P: AsRef<Path>>(&self, link_path: P) -> anyhow::Result<Option<Reason>> {
         let link_path = link_path.as_ref();
-        let target = fs::read_link(link_path)
-            .with_context(|| format!("failed to read symbolic link {link_path:?}"))?;
-        log::trace!("processing {link_path:?} -> {target:?}");
-        let metadata = match fs::symlink_metadata(&target) {
-            Ok(m) => m,
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+        match self.verdict(link_path)? {
+            Verdict::Expired(reason) => Ok(Some(reason)),
+            Verdict::Dangling => {
+                self.statistic.dangling.increase();
+                if self.options.selection.clean_dangling {
+                    return self.dangling_reason(link_path).map(Some);
+                }
                 log::debug!("target of {link_path:?} not found, skip");
-                return Ok(None);
+                Ok(None)
+            }
+            Verdict::Censored => {
+                self.statistic.censored.increase();
+                log::debug!("target of {link_path:?} is not accessible, skip");
+                Ok(None)
+            }
+            Verdict::Keep(keep) => {
+                match keep {
+                    KeepReason::Excluded => self.statistic.excluded.increase(),
+                    KeepReason::Pinned | KeepReason::Marker { .. } => {
+                        self.statistic.pinned.increase()
+                    }
+                    _ => (),
+                }
+                log::debug!("keep {link_path:?}: {keep}");
+                Ok(None)
+            }
+        }
+    }
+
+    /// The final decision on a GC root, without any side effect
+    fn verdict(&self, link_path: &Path) -> anyhow::Result<Verdict> {
+        match self.evaluate(link_path)? {
+            Verdict::Expired(mut reason) => {
+                match self.check_shared_target(link_path, &mut reason)? {
+                    Some(keep) => Ok(Verdict::Keep(keep)),
+                    None => Ok(Verdict::Expired(reason)),
+                }
+            }
+            verdict => Ok(verdict),
+        }
+    }
+
+    /// Apply the path conditions, exclusions, pins and owner filters to a GC root,
+    /// returns the verdict if it is not selected
+    fn select(&self, link_path: &Path, target: &Path) -> anyhow::Result<Result<Selected, Verdict>> {
+        if !self.selection.is_empty() && !matches_any(&self.selection, target) {
+            return Ok(Err(Verdict::Keep(KeepReason::NotSelected)));
+        }
+        if !self.link_selection.is_empty() && !matches_any(&self.link_selection, link_path) {
+            return Ok(Err(Verdict::Keep(KeepReason::NotSelected)));
+        }
+        if self.exclusions.is_match(link_path) || self.exclusions.is_match(target) {
+            return Ok(Err(Verdict::Keep(KeepReason::Excluded)));
+        }
+        if self.pins.is_match(link_path) || self.pins.is_match(target) {
+            return Ok(Err(Verdict::Keep(KeepReason::Pinned)));
+        }
+        if !self.options.selection.no_keep_marker {
+            if let Some(marker) = self.keep_marker(target)? {
+                return Ok(Err(Verdict::Keep(KeepReason::Marker { path: marker })));
+            }
+        }
+        let metadata = match fs::symlink_metadata(target) {
+            Ok(m) => m,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Err(Verdict::Dangling)),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                return Ok(Err(Verdict::Censored))
             }
             e => e.with_context(|| format!("failed to read metadata of file {target:?}"))?,
         };
-        if self.options.owned_only {
-            let file_uid = metadata.uid();
-            if file_uid != self.uid {
-                log::debug!("ignore {target:?} due to uid mismatch: file uid == {file_uid}, process uid == {process_uid}",
-                  process_uid = self.uid);
-                return Ok(None);
+        let store_path = if self.options.selection.store_path_kind.is_some()
+            || self.store_name.is_some()
+            || self.options.selection.keep_in_use
+        {
+            self.resolve(target).ok().and_then(|p| self.store_path(&p))
+        } else {
+            None
+        };
+        if let Some(kind) = self.options.selection.store_path_kind {
+            let is_drv = store_path.as_deref().map(store::is_derivation);
+            let matched = match kind {
+                StorePathKind::Drv => is_drv == Some(true),
+                StorePathKind::Output => is_drv == Some(false),
+            };
+            if !matched {
+                return Ok(Err(Verdict::Keep(KeepReason::NotSelected)));
             }
         }
-        let target_mtime = metadata
-            .modified()
-            .with_context(|| format!("failed to get modified time of file {target:?}"))?;
+        if let Some(regex) = &self.store_name {
+            let name = store_path.as_deref().and_then(store::store_name);
+            if !name.is_some_and(|n| regex.is_match(n)) {
+                return Ok(Err(Verdict::Keep(KeepReason::NotSelected)));
+            }
+        }
+        let file_uid = metadata.uid();
+        if self.options.selection.owned_only && file_uid != self.uid {
+            return Ok(Err(Verdict::Keep(KeepReason::NotOwned { uid: file_uid })));
+        }
+        if !self.owners.is_empty() || self.options.selection.owner_uid_range.is_some() {
+            let matched = self.owners.contains(&file_uid)
+                || self
+                    .options
+                    .selection
+                    .owner_uid_range
+                    .as_ref()
+                    .is_some_and(|r| r.contains(&file_uid));
+            if !matched {
+                return Ok(Err(Verdict::Keep(KeepReason::OwnerMismatch {
+                    uid: file_uid,
+                })));
+            }
+        }
+        Ok(Ok(Selected {
+            metadata,
+            store_path,
+        }))
+    }
+
+    /// Decide whether a GC root is expired on its own, without any side effect,
+    /// the current generation of a profile is never expired whatever the policy says
+    fn evaluate(&self, link_path: &Path) -> anyhow::Result<Verdict> {
+        let verdict = self.evaluate_policy(link_path)?;
+        if matches!(verdict, Verdict::Expired(_) | Verdict::Dangling)
+            && nix_config::is_current_generation(link_path)
+        {
+            log::warn!(
+                "refuse to remove {link_path:?}, it is the current generation of its profile"
+            );
+            return Ok(Verdict::Keep(KeepReason::CurrentGeneration));
+        }
+        Ok(verdict)
+    }
+
+    fn evaluate_policy(&self, link_path: &Path) -> anyhow::Result<Verdict> {
+        let target = match fs::read_link(link_path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Ok(Verdict::Censored),
+            e => e.with_context(|| format!("failed to read symbolic link {link_path:?}"))?,
+        };
+        log::trace!("processing {link_path:?} -> {target:?}");
+        let Selected {
+            metadata,
+            store_path,
+        } = match self.select(link_path, &target)? {
+            Ok(selected) => selected,
+            Err(Verdict::Dangling) => return self.dangling_verdict(link_path),
+            Err(verdict) => return Ok(verdict),
+        };
+        if self.latest.contains(link_path) {
+            return Ok(Verdict::Keep(KeepReason::Latest));
+        }
+        if self.active_generations.contains(link_path) {
+            return Ok(Verdict::Keep(KeepReason::ActiveGeneration));
+        }
+        let file_uid = metadata.uid();
+        let reference_time = self.reference_time(link_path, &target, &metadata)?;
         let elapsed = self
             .now
-            .duration_since(target_mtime)
+            .duration_since(reference_time)
             .unwrap_or_else(|_| Duration::new(0, 0));
         log::trace!("elapsed: {}", humantime::format_duration(elapsed));
-        if elapsed <= self.options.period {
-            return Ok(None);
+        if let Some(min_age) = self.options.selection.min_age {
+            if elapsed < min_age {
+                return Ok(Verdict::Keep(KeepReason::BelowMinAge { elapsed, min_age }));
+            }
         }
+        let period = *self
+            .period_overrides
+            .get(&file_uid)
+            .unwrap_or(&self.options.selection.period);
+        let forced = if self.denylist.is_match(link_path) || self.denylist.is_match(&target) {
+            Some(Forced::Denylist)
+        } else if self.superseded.contains(link_path) {
+            Some(Forced::Superseded)
+        } else if self.companions.lock().unwrap().contains(link_path) {
+            Some(Forced::Companion)
+        } else {
+            None
+        };
+        if let Some(forced) = forced {
+            log::debug!(
+                "ignore retention period of {link_path:?}: {}",
+                forced.describe()
+            );
+        } else if elapsed <= period {
+            return Ok(Verdict::Keep(KeepReason::Young { elapsed, period }));
+        }
+        if let (Some(min_size), None) = (self.options.selection.min_closure_size, forced) {
+            if !self.validate(&target)? {
+                return Ok(Verdict::Keep(KeepReason::UnknownClosure));
+            }
+            let size = store::is_valid(&target).and_then(|valid| {
+                if valid {
+                    store::closure_size(&target).map(Some)
+                } else {
+                    Ok(None)
+                }
+            });
+            let size = match size {
+                Ok(Some(size)) => size,
+                Ok(None) => return Ok(Verdict::Keep(KeepReason::UnknownClosure)),
+                Err(e) => {
+                    log::warn!("failed to query closure size of {target:?}: {e:#}");
+                    return Ok(Verdict::Keep(KeepReason::UnknownClosure));
+                }
+            };
+            log::trace!("closure size of {target:?}: {size}");
+            if size < min_size {
+                return Ok(Verdict::Keep(KeepReason::SmallClosure { size }));
+            }
+        }
+        if let Some((dir, pid)) = self.in_session(link_path, &target) {
+            return Ok(Verdict::Keep(KeepReason::InSession { dir, pid }));
+        }
+        if let Some((path, pid)) = self.in_use(store_path.as_deref()) {
+            return Ok(Verdict::Keep(KeepReason::InUse { path, pid }));
+        }
+
+        let store = self
+            .resolve(&target)
+            .ok()
+            .and_then(|p| self.store_of(&p))
+            .map(|(store, _)| store.to_path_buf());
+        let snapshot = Snapshot::new(&metadata, fs::read_link(&target).ok());
+        Ok(Verdict::Expired(Reason {
+            target,
+            elapsed,
+            source: self.options.selection.age_source,
+            forced,
+            store,
+            snapshot,
+        }))
+    }
+
+    /// Find a live direnv session of the project containing the GC root or its target
+    fn in_session(&self, link_path: &Path, target: &Path) -> Option<(PathBuf, u32)> {
+        self.runtime
+            .sessions
+            .iter()
+            .find(|(dir, _)| link_path.starts_with(dir) || target.starts_with(dir))
+            .map(|(dir, pid)| (dir.clone(), *pid))
+    }
+
+    /// Find a store path in the closure of `store_path` used by a running process
+    fn in_use(&self, store_path: Option<&Path>) -> Option<(PathBuf, u32)> {
+        let store_path = store_path?;
+        if self.runtime.paths.is_empty() {
+            return None;
+        }
+        if let Some(pid) = self.runtime.paths.get(store_path) {
+            return Some((store_path.to_owned(), *pid));
+        }
+        let requisites = match store::requisites(store_path) {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!(
+                    "failed to query closure of {store_path:?}, only check the path itself: {e:#}"
+                );
+                return None;
+            }
+        };
+        requisites
+            .into_iter()
+            .find_map(|p| self.runtime.paths.get(&p).map(|pid| (p, *pid)))
+    }
+
+    /// Find a valid `.angrr-keep` marker in any ancestor directory of `target`
+    fn keep_marker(&self, target: &Path) -> anyhow::Result<Option<PathBuf>> {
+        let ancestors = target.ancestors().skip(1);
+        for dir in ancestors.filter(|d| d.parent().is_some()) {
+            let marker = dir.join(KEEP_MARKER);
+            let metadata = match fs::symlink_metadata(&marker) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            if !metadata.is_file() {
+                log::debug!("ignore keep marker {marker:?}, not a regular file");
+                continue;
+            }
+            // anyone could plant a marker in directories like `/tmp`
+            if fs::metadata(dir).is_ok_and(|m| m.mode() & (libc::S_IWOTH | libc::S_ISVTX) != 0) {
+                log::debug!(
+                    "ignore keep marker {marker:?}, its directory is writable by all users"
+                );
+                continue;
+            }
+            let mut content = Vec::new();
+            OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+                .open(&marker)
+                .and_then(|file| file.take(MAX_KEEP_MARKER_SIZE).read_to_end(&mut content))
+                .with_context(|| format!("failed to read keep marker {marker:?}"))?;
+            let content = String::from_utf8_lossy(&content);
+            let ttl = content.trim();
+            if ttl.is_empty() {
+                return Ok(Some(marker));
+            }
+            let ttl = match humantime::parse_duration(ttl) {
+                Ok(d) => d,
+                Err(e) => {
+                    log::warn!("invalid TTL in keep marker {marker:?}, keep forever: {e}");
+                    return Ok(Some(marker));
+                }
+            };
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|t| self.now.duration_since(t).ok())
+                .unwrap_or_default();
+            if age <= ttl {
+                return Ok(Some(marker));
+            }
+            log::debug!("keep marker {marker:?} expired");
+        }
+        Ok(None)
+    }
+
+    /// Check other GC roots pointing to the same target
+    ///
+    /// The target is only expired if all these roots are expired,
+    /// and the youngest of them defines the age of the target.
+    fn check_shared_target(
+        &self,
+        link_path: &Path,
+        reason: &mut Reason,
+    ) -> anyhow::Result<Option<KeepReason>> {
+        if let Some(links) = self.shared_targets()?.get(&reason.target) {
+            for other in links.iter().filter(|l| *l != link_path) {
+                match self.evaluate(other)? {
+                    Verdict::Expired(other_reason) => {
+                        reason.elapsed = reason.elapsed.min(other_reason.elapsed);
+                    }
+                    _ => return Ok(Some(KeepReason::Referenced { by: other.clone() })),
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// A dangling GC root removed by `--clean-dangling`, aged by its own modified time
+    /// Dangling roots are still kept by `--min-age`, aged by their own modified time
+    fn dangling_verdict(&self, link_path: &Path) -> anyhow::Result<Verdict> {
+        if let Some(min_age) = self.options.selection.min_age {
+            let metadata = fs::symlink_metadata(link_path)
+                .with_context(|| format!("failed to read metadata of GC root {link_path:?}"))?;
+            let elapsed = metadata
+                .modified()
+                .ok()
+                .and_then(|t| self.now.duration_since(t).ok())
+                .unwrap_or_default();
+            if elapsed < min_age {
+                return Ok(Verdict::Keep(KeepReason::BelowMinAge { elapsed, min_age }));
+            }
+        }
+        Ok(Verdict::Dangling)
+    }
 
-        Ok(Some(Reason { target, elapsed }))
+    fn dangling_reason(&self, link_path: &Path) -> anyhow::Result<Reason> {
+        let target = fs::read_link(link_path)
+            .with_context(|| format!("failed to read symbolic link {link_path:?}"))?;
+        let metadata = fs::symlink_metadata(link_path)
+            .with_context(|| format!("failed to read metadata of GC root {link_path:?}"))?;
+        let elapsed = metadata
+            .modified()
+            .ok()
+            .and_then(|t| self.now.duration_since(t).ok())
+            .unwrap_or_default();
+        Ok(Reason {
+            snapshot: Snapshot::new(&metadata, Some(target.clone())),
+            target,
+            elapsed,
+            source: AgeSource::LinkMtime,
+            forced: Some(Forced::Dangling),
+            store: None,
+        })
+    }
+
+    /// Remove a file if it has not changed since `snapshot`, returns whether it was removed
+    fn remove_path(&self, path: &Path, snapshot: &Snapshot) -> anyhow::Result<bool> {
+        if !self.options.dry_run {
+            let removed = unlink::unlink_if_unchanged(path, snapshot)
+                .with_context(|| format!("failed to remove {:?}", path))?;
+            if !removed {
+                log::warn!("{path:?} changed since it was scanned, skip");
+                return Ok(false);
+            }
+            if let Some(parent) = path.parent() {
+                self.removed_from.lock().unwrap().insert(parent.to_owned());
+            }
+        }
+        self.report_progress(Phase::Remove, Some(path))?;
+        let mut out = self.output.lock().unwrap();
+        out.output(path, &self.options.output_delimiter)?;
+        Ok(true)
+    }
+
+    /// The time the age of a GC root is computed from, according to `--age-source`
+    fn reference_time(
+        &self,
+        link_path: &Path,
+        target: &Path,
+        metadata: &Metadata,
+    ) -> anyhow::Result<SystemTime> {
+        let mtime = || {
+            metadata
+                .modified()
+                .with_context(|| format!("failed to get modified time of file {target:?}"))
+        };
+        let atime = || {
+            metadata
+                .accessed()
+                .with_context(|| format!("failed to get access time of file {target:?}"))
+        };
+        let ctime = || {
+            let secs = u64::try_from(metadata.ctime()).unwrap_or(0);
+            let nanos = u32::try_from(metadata.ctime_nsec()).unwrap_or(0);
+            UNIX_EPOCH + Duration::new(secs, nanos)
+        };
+        let link_mtime = || {
+            fs::symlink_metadata(link_path)
+                .and_then(|m| m.modified())
+                .with_context(|| format!("failed to get modified time of GC root {link_path:?}"))
+        };
+        Ok(match self.options.selection.age_source {
+            AgeSource::Mtime => mtime()?,
+            AgeSource::Atime => atime()?,
+            AgeSource::Ctime => ctime(),
+            AgeSource::LinkMtime => link_mtime()?,
+            AgeSource::Max => [mtime()?, atime()?, ctime(), link_mtime()?]
+                .into_iter()
+                .max()
+                .unwrap(),
+        })
+    }
+
+    /// The `--store` containing the canonical path `path`,
+    /// and the prefix of `path` it was found by, either itself or its canonical path
+    fn store_of(&self, path: &Path) -> Option<(&Path, &Path)> {
+        self.options
+            .selection
+            .store
+            .iter()
+            .zip(&self.canonical_stores)
+            .find_map(|(store, canonical)| {
+                [store, canonical]
+                    .into_iter()
+                    .find(|prefix| path.starts_with(prefix))
+                    .map(|prefix| (store.as_path(), prefix.as_path()))
+            })
+    }
+
+    /// The top-level store path containing the canonical path `path` in any `--store`,
+    /// below the `--store` as given even if found by its canonical path
+    fn store_path(&self, path: &Path) -> Option<PathBuf> {
+        let (store, prefix) = self.store_of(path)?;
+        let relative = path.strip_prefix(prefix).ok()?;
+        store::store_path(store, &store.join(relative))
+    }
+
+    /// Canonicalize a GC root target
+    ///
+    /// With `--lexical-fallback`, transient failures are retried a few times, then symbolic links
+    /// are followed lexically until a store is reached, without accessing the final target.
+    fn resolve<P: AsRef<Path>>(&self, path: P) -> io::Result<PathBuf> {
+        let path = path.as_ref();
+        if !self.options.selection.lexical_fallback {
+            return fs::canonicalize(path);
+        }
+        let mut attempt = 1;
+        loop {
+            match fs::canonicalize(path) {
+                Ok(p) => return Ok(p),
+                Err(e) if attempt >= CANONICALIZE_ATTEMPTS || !is_transient(&e) => {
+                    log::debug!("failed to canonicalize {path:?}, resolve lexically: {e}");
+                    break;
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(100 * attempt as u64)),
+            }
+            attempt += 1;
+        }
+        let mut current = normalize_lexically(&std::path::absolute(path)?);
+        for _ in 0..MAX_SYMLINK_HOPS {
+            if self.store_of(&current).is_some() {
+                return Ok(current);
+            }
+            match fs::read_link(&current) {
+                Ok(target) => {
+                    let parent = current.parent().unwrap_or(Path::new("/"));
+                    current = normalize_lexically(&parent.join(target));
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => return Ok(current),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::other(format!(
+            "too many levels of symbolic links resolving {path:?}"
+        )))
     }
 
     fn validate<P: AsRef<Path>>(&self, target: P) -> anyhow::Result<bool> {
         let target = target.as_ref();
-        let final_target = fs::canonicalize(target)
-            .with_context(|| format!("failed to canonicalize {target:?} for validation"))?;
-        Ok(final_target.starts_with(&self.options.store))
+        // a broken chain fails validation like any other target outside the store
+        let final_target = match self.resolve(target) {
+            Ok(p) => p,
+            Err(e) => {
+                log::debug!("failed to canonicalize {target:?} for validation: {e}");
+                return Ok(false);
+            }
+        };
+        if self.store_of(&final_target).is_some() {
+            return Ok(true);
+        }
+        Ok(self.options.selection.validate_store_pattern
+            && store::has_store_path_name(&final_target))
     }
 
     fn validate_and_prompt<P: AsRef<Path>>(&self, target: P) -> anyhow::Result<bool> {
@@ -242,10 +1514,13 @@ impl RunContext {
             };
             writeln!(
                 term,
-                "{}, target {:?} does not point into store {:?}",
+                "{}, target {:?} does not point into {}",
                 fail_message_style.apply_to("Validation failed"),
                 term.style().underlined().apply_to(&target),
-                self.options.store
+                match self.options.selection.store.as_slice() {
+                    [store] => format!("store {store:?}"),
+                    stores => format!("any of stores {stores:?}"),
+                }
             )?;
             if self.options.force {
                 Ok(true)
@@ -269,24 +1544,192 @@ impl RunContext {
             .context("failed to prompt")
     }
 
-    fn output_writer(options: &RunOptions) -> anyhow::Result<Box<dyn OutputWriter>> {
-        match &options.output {
+    fn output(options: &RunOptions) -> anyhow::Result<Output> {
+        let mut first_output = true;
+        let mut pending = None;
+        let writer: Box<dyn OutputWriter> = match &options.output {
             Some(path) => {
-                let mut writer: Box<dyn OutputWriter> = if *path == PathBuf::from("-") {
+                let mut writer: Box<dyn OutputWriter> = if path.as_os_str() == "-" {
                     Box::new(stdout())
+                } else if options.output_append {
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .with_context(|| format!("failed to open output file {path:?}"))?;
+                    // separate appended paths from existing content
+                    first_output = file
+                        .metadata()
+                        .with_context(|| format!("failed to read metadata of file {path:?}"))?
+                        .len()
+                        == 0;
+                    Box::new(file)
+                } else if !is_replaceable(path) {
+                    // pipes, devices and symbolic links are written through
+                    let file = OpenOptions::new()
+                        .create(true)
+                        .write(true)
+                        .truncate(true)
+                        .open(path)
+                        .with_context(|| format!("failed to open output file {path:?}"))?;
+                    Box::new(file)
                 } else {
-                    Box::new(
-                        File::create(path)
-                            .with_context(|| format!("failed to create output file {path:?}"))?,
-                    )
+                    // write to a temporary file next to the output file,
+                    // and rename it on finish so consumers never see a truncated list
+                    let parent = match path.parent() {
+                        Some(p) if !p.as_os_str().is_empty() => p,
+                        _ => Path::new("."),
+                    };
+                    let (file, temp_path) = tempfile::Builder::new()
+                        .prefix(".angrr-output-")
+                        .permissions(Permissions::from_mode(0o666))
+                        .tempfile_in(parent)
+                        .with_context(|| format!("failed to create output file {path:?}"))?
+                        .into_parts();
+                    pending = Some(PendingOutput {
+                        temp_path,
+                        path: path.clone(),
+                    });
+                    Box::new(file)
                 };
                 if !options.output_unbuffered {
                     writer = Box::new(BufWriter::new(writer));
                 }
-                Ok(writer)
+                writer
             }
-            None => Ok(Box::new(sink())),
+            None => Box::new(sink()),
+        };
+        Ok(Output {
+            writer,
+            first_output,
+            pending,
+        })
+    }
+
+    fn exclusions(options: &RunOptions) -> anyhow::Result<PathMatcher> {
+        let delimiter = if options.selection.null_exclude_delimiter {
+            b'\0'
+        } else {
+            b'\n'
+        };
+        let mut exclusions = PathMatcher::default();
+        for file in &options.selection.exclude_from {
+            exclusions.load(file, delimiter)?;
+        }
+        for pattern in &options.selection.exclude_regex {
+            exclusions.add_regex(pattern)?;
+        }
+        for glob in &options.selection.exclude_glob {
+            exclusions.add_glob(glob)?;
+        }
+        Ok(exclusions)
+    }
+
+    /// Mimic `nix-collect-garbage --delete-older-than`, removing old generations of profiles
+    fn collect_garbage_compat(options: &mut RunOptions, uid: u32) {
+        options.selection.directory = nix_config::profile_dirs(uid);
+        options
+            .selection
+            .link_path_regex
+            .push(GENERATION_REGEX.to_owned());
+        options.selection.remove = RemoveMode::Root;
+        options.selection.age_source = AgeSource::LinkMtime;
+        options.selection.owned_only = false;
+    }
+
+    /// The current generation of every profile, and the newest generation older than the
+    /// retention period, which was active at the cutoff, like `nix-collect-garbage` keeps
+    fn active_generations(&self) -> anyhow::Result<HashSet<PathBuf>> {
+        let cutoff = self.now.checked_sub(self.options.selection.period);
+        let mut active = HashSet::new();
+        let mut at_cutoff: HashMap<PathBuf, (u64, PathBuf)> = HashMap::new();
+        self.scan_roots(|link_path| {
+            let Some((profile, number)) = nix_config::generation(&link_path) else {
+                return Ok(());
+            };
+            if nix_config::is_current_generation(&link_path) {
+                active.insert(link_path.clone());
+            }
+            let created = fs::symlink_metadata(&link_path).and_then(|m| m.modified());
+            let older = match (created, cutoff) {
+                (Ok(created), Some(cutoff)) => created < cutoff,
+                _ => false,
+            };
+            let newer_known = at_cutoff.get(&profile).is_some_and(|(n, _)| *n > number);
+            if older && !newer_known {
+                at_cutoff.insert(profile, (number, link_path));
+            }
+            Ok(())
+        })?;
+        active.extend(at_cutoff.into_values().map(|(_, link)| link));
+        Ok(active)
+    }
+
+    /// Expand globs in `--directory`
+    fn directories(directories: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+        let mut expanded = Vec::new();
+        for dir in directories {
+            if !dir.to_str().is_some_and(glob::is_glob) {
+                expanded.push(dir.clone());
+                continue;
+            }
+            let matched = glob::expand_directories(dir)?;
+            if matched.is_empty() {
+                log::warn!("no directory matches {dir:?}");
+            }
+            log::debug!("{dir:?} expanded to {matched:?}");
+            expanded.extend(matched);
+        }
+        Ok(expanded)
+    }
+
+    /// Collect `--root` and `--roots-from`, `None` if neither is given
+    fn explicit_roots(options: &RunOptions) -> anyhow::Result<Option<Vec<PathBuf>>> {
+        if options.selection.root.is_empty() && options.selection.roots_from.is_empty() {
+            return Ok(None);
+        }
+        let delimiter = if options.selection.null_roots_delimiter {
+            b'\0'
+        } else {
+            b'\n'
+        };
+        let mut roots = options.selection.root.clone();
+        for file in &options.selection.roots_from {
+            let content = if file.as_os_str() == "-" {
+                let mut content = Vec::new();
+                io::stdin()
+                    .read_to_end(&mut content)
+                    .context("failed to read GC roots from standard input")?;
+                content
+            } else {
+                fs::read(file).with_context(|| format!("failed to read GC roots from {file:?}"))?
+            };
+            roots.extend(
+                content
+                    .split(|b| *b == delimiter)
+                    .filter(|r| !r.is_empty())
+                    .map(|r| PathBuf::from(OsStr::from_bytes(r))),
+            );
         }
+        roots
+            .into_iter()
+            .map(|r| {
+                std::path::absolute(&r)
+                    .with_context(|| format!("failed to get absolute path of {r:?}"))
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Some)
+    }
+
+    /// Load the `pins` and `denylist` files from all state directories
+    fn state_lists(state_dirs: &[PathBuf]) -> anyhow::Result<(PathMatcher, PathMatcher)> {
+        let mut pins = PathMatcher::default();
+        let mut denylist = PathMatcher::default();
+        for dir in state_dirs {
+            pins.load_if_exists(dir.join("pins"), b'\n')?;
+            denylist.load_if_exists(dir.join("denylist"), b'\n')?;
+        }
+        Ok((pins, denylist))
     }
 }
 
@@ -295,10 +1738,24 @@ impl ToRemove<'_> {
         &self.context.options
     }
 
+    fn is_dangling(&self) -> bool {
+        matches!(self.reason.forced, Some(Forced::Dangling))
+    }
+
     fn notify(&self, action: Action, with_reason: bool) -> anyhow::Result<()> {
         let mut term = self.context.term.clone();
         let reason_indent = 2;
-        if self.options().remove_root {
+        if self.is_dangling() {
+            writeln!(
+                term,
+                "{} dangling GC root {:?}",
+                action.format_with_style(&term),
+                self.link_path
+            )?;
+            if with_reason {
+                term.write_line(&add_indent(&self.format_reason(&term), reason_indent))?;
+            }
+        } else if self.options().selection.remove == RemoveMode::Root {
             // remove link
             writeln!(
                 term,
@@ -312,6 +1769,28 @@ impl ToRemove<'_> {
                     reason_indent,
                 ))?;
             }
+        } else if self.options().selection.remove == RemoveMode::Both {
+            // remove target and links
+            if self.other_links.is_empty() {
+                writeln!(
+                    term,
+                    "{} {:?} and GC root {:?}",
+                    action.format_with_style(&term),
+                    self.reason.target,
+                    self.link_path
+                )?;
+            } else {
+                writeln!(
+                    term,
+                    "{} {:?} and {} GC roots",
+                    action.format_with_style(&term),
+                    self.reason.target,
+                    self.other_links.len() + 1
+                )?;
+            }
+            if with_reason {
+                term.write_line(&add_indent(&self.format_reason(&term), reason_indent))?;
+            }
         } else {
             // remove target
             writeln!(
@@ -321,35 +1800,85 @@ impl ToRemove<'_> {
                 self.reason.target
             )?;
             if with_reason {
-                term.write_line(&add_indent(
-                    &self.reason.format_with_style_no_target(&term),
-                    reason_indent,
-                ))?;
+                term.write_line(&add_indent(&self.format_reason(&term), reason_indent))?;
             }
         }
         Ok(())
     }
 
+    fn format_reason(&self, term: &Term) -> String {
+        let mut text = self.reason.format_with_style_no_target(term);
+        if let (Some(store), [_, _, ..]) = (
+            &self.reason.store,
+            self.options().selection.store.as_slice(),
+        ) {
+            text.push_str(&format!("\nin store {store:?}"));
+        }
+        if !self.other_links.is_empty() {
+            text.push_str(&format!(
+                "\nreferenced by {} GC roots",
+                term.style().bold().apply_to(self.other_links.len() + 1)
+            ));
+        }
+        text
+    }
+
     fn remove(&self) -> anyhow::Result<()> {
-        let path_to_remove = if self.options().remove_root {
-            &self.link_path
-        } else {
-            // validate before remove target
-            let target = &self.reason.target;
-            if !self.context.validate_and_prompt(target)? {
-                self.notify(Action::Ignored, false)?;
-                return Ok(());
+        if self.is_dangling() {
+            // nothing in the store to validate
+            self.notify(Action::Remove, false)?;
+            if self
+                .context
+                .remove_path(&self.link_path, &self.reason.snapshot)?
+            {
+                self.context.statistic.removed.increase();
             }
-            target
-        };
+            return Ok(());
+        }
+        let remove = self.options().selection.remove;
+        // validate right before removal, the target may have left the store since the scan
+        if !self.context.validate_and_prompt(&self.reason.target)? {
+            self.notify(Action::Ignored, false)?;
+            return Ok(());
+        }
         self.notify(Action::Remove, false)?;
-        if !self.options().dry_run {
-            fs::remove_file(path_to_remove)
-                .with_context(|| format!("failed to remove {:?}", path_to_remove))?;
+        if self.options().delete_closure {
+            let store_path = self
+                .context
+                .resolve(&self.reason.target)
+                .ok()
+                .and_then(|p| self.context.store_path(&p));
+            if let Some(store_path) = store_path {
+                let mut removed = self.context.removed_store_paths.lock().unwrap();
+                removed.push(store_path);
+            }
         }
-        self.context.statistic.removed.increase();
-        let mut out = self.context.output.lock().unwrap();
-        out.output(path_to_remove, &self.options().output_delimiter)?;
+        let target = &self.reason.target;
+        // all GC roots of the target are expected to still point to it
+        let link_snapshot = Snapshot::link(target.clone());
+        let removed = match remove {
+            RemoveMode::Target => {
+                if self.context.remove_path(target, &self.reason.snapshot)? {
+                    1 + self.other_links.len()
+                } else {
+                    0
+                }
+            }
+            RemoveMode::Root => {
+                usize::from(self.context.remove_path(&self.link_path, &link_snapshot)?)
+            }
+            RemoveMode::Both => {
+                // the GC roots of a replaced target protect the new one, keep them
+                let mut removed = 0;
+                if self.context.remove_path(target, &self.reason.snapshot)? {
+                    for link in iter::once(&self.link_path).chain(&self.other_links) {
+                        removed += usize::from(self.context.remove_path(link, &link_snapshot)?);
+                    }
+                }
+                removed
+            }
+        };
+        self.context.statistic.removed.add(removed);
         Ok(())
     }
 }
@@ -371,20 +1900,106 @@ impl Action {
 
 impl Reason {
     fn format_with_style(&self, term: &Term) -> String {
-        let Self { target, elapsed } = self;
         format!(
-            "target {:?}\nwas last modified {} ago",
-            term.style().underlined().apply_to(target),
-            term.style().bold().apply_to(format_duration(*elapsed))
+            "target {:?}\n{}",
+            term.style().underlined().apply_to(&self.target),
+            self.format_with_style_no_target(term)
         )
     }
 
     fn format_with_style_no_target(&self, term: &Term) -> String {
-        let Self { elapsed, .. } = self;
-        format!(
-            "was last modified {} ago",
+        let Self {
+            elapsed,
+            source,
+            forced,
+            ..
+        } = self;
+        let mut text = format!(
+            "{} {} ago",
+            source.describe(),
             term.style().bold().apply_to(format_duration(*elapsed))
-        )
+        );
+        if let Some(forced) = forced {
+            text.push('\n');
+            text.push_str(forced.describe());
+        }
+        text
+    }
+}
+
+impl fmt::Display for KeepReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeepReason::NotSelected => write!(f, "not selected by the path conditions"),
+            KeepReason::Latest => write!(f, "one of the latest roots of its group"),
+            KeepReason::CurrentGeneration => write!(f, "current generation of its profile"),
+            KeepReason::ActiveGeneration => {
+                write!(f, "generation active now or at the retention cutoff")
+            }
+            KeepReason::Excluded => write!(f, "excluded"),
+            KeepReason::Pinned => write!(f, "pinned"),
+            KeepReason::Marker { path } => write!(f, "pinned by keep marker {path:?}"),
+            KeepReason::NotOwned { uid } => {
+                write!(f, "target owned by uid {uid}, not by the current user")
+            }
+            KeepReason::OwnerMismatch { uid } => {
+                write!(f, "target owned by uid {uid}, not by a selected owner")
+            }
+            KeepReason::Young { elapsed, period } => write!(
+                f,
+                "age {} is within retention period {}",
+                format_duration(*elapsed),
+                format_duration(*period)
+            ),
+            KeepReason::BelowMinAge { elapsed, min_age } => write!(
+                f,
+                "age {} is below minimum age {}",
+                format_duration(*elapsed),
+                format_duration(*min_age)
+            ),
+            KeepReason::UnknownClosure => {
+                write!(
+                    f,
+                    "closure size of paths outside store or invalid paths is unknown"
+                )
+            }
+            KeepReason::SmallClosure { size } => write!(f, "closure size {size} is too small"),
+            KeepReason::Referenced { by } => {
+                write!(f, "target is still referenced by GC root {by:?}")
+            }
+            KeepReason::InUse { path, pid } => {
+                write!(f, "{path:?} in closure is used by process {pid}")
+            }
+            KeepReason::InSession { dir, pid } => {
+                write!(
+                    f,
+                    "project {dir:?} has a live direnv session in process {pid}"
+                )
+            }
+        }
+    }
+}
+
+impl Forced {
+    fn describe(&self) -> &'static str {
+        match self {
+            Forced::Denylist => "listed in the denylist",
+            Forced::Superseded => "superseded by newer roots of its group",
+            Forced::Companion => "derivation of another expired output root",
+            Forced::Dangling => "target does not exist",
+        }
+    }
+}
+
+impl AgeSource {
+    fn describe(&self) -> &'static str {
+        match self {
+            AgeSource::Mtime => "was last modified",
+            AgeSource::Atime => "was last accessed",
+            AgeSource::Ctime => "was last changed",
+            AgeSource::LinkMtime => "GC root was last modified",
+            AgeSource::Max => "was last active",
+        }
     }
 }
 
@@ -393,12 +2008,20 @@ impl Statistics {
         let traversed = self.traversed.done();
         let candidate = self.candidate.done();
         let removed = self.removed.done();
+        let excluded = self.excluded.done();
+        let pinned = self.pinned.done();
+        let dangling = self.dangling.done();
+        let censored = self.censored.done();
         let invalid = self.invalid.done();
         let kept = traversed - removed;
         let num_style = |n| term.style().bold().apply_to(n);
         [
             format!("traversed: {}", num_style(traversed)),
             format!("candidate: {}", num_style(candidate)),
+            format!("excluded:  {}", num_style(excluded)),
+            format!("pinned:    {}", num_style(pinned)),
+            format!("dangling:  {}", num_style(dangling)),
+            format!("censored:  {}", num_style(censored)),
             format!("removed:   {}", num_style(removed)),
             format!("invalid:   {}", num_style(invalid)),
             format!("kept:      {}", num_style(kept)),
@@ -409,7 +2032,15 @@ impl Statistics {
 
 impl Counter {
     fn increase(&self) {
-        self.0.fetch_add(1, Ordering::Relaxed);
+        self.add(1);
+    }
+
+    fn add(&self, n: usize) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn value(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
     }
 
     fn done(self) -> usize {
@@ -418,6 +2049,16 @@ impl Counter {
 }
 
 impl Output {
+    fn finish(mut self) -> anyhow::Result<()> {
+        self.writer.flush().context("failed to flush output")?;
+        if let Some(PendingOutput { temp_path, path }) = self.pending {
+            temp_path
+                .persist(&path)
+                .with_context(|| format!("failed to write output file {path:?}"))?;
+        }
+        Ok(())
+    }
+
     fn output<P: AsRef<Path>>(&mut self, path: P, delimiter: &OsStr) -> anyhow::Result<()> {
         let p = path.as_ref();
         if !self.first_output {
@@ -430,6 +2071,121 @@ impl Output {
     }
 }
 
+/// Visit GC roots in `directories` and their subdirectories up to `max_depth`
+fn scan_directories<F>(
+    directories: &[PathBuf],
+    max_depth: Option<usize>,
+    mut f: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(PathBuf) -> anyhow::Result<()>,
+{
+    for path in directories {
+        let directory =
+            fs::read_dir(path).with_context(|| format!("failed to open directory {path:?}"))?;
+        walk_roots(path, directory, 1, max_depth, &mut f)?;
+    }
+    Ok(())
+}
+
+/// Visit GC roots in `directory` and its subdirectories up to `max_depth`,
+/// symbolic links to directories are GC roots themselves and never followed
+fn walk_roots<F>(
+    path: &Path,
+    directory: fs::ReadDir,
+    depth: usize,
+    max_depth: Option<usize>,
+    f: &mut F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(PathBuf) -> anyhow::Result<()>,
+{
+    for entry in directory {
+        let entry = entry
+            .with_context(|| format!("failed to read directory entry from directory {path:?}"))?;
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("failed to get file type of {:?}", entry.path()))?;
+        if !file_type.is_dir() {
+            f(entry.path())?;
+            continue;
+        }
+        let subdirectory = entry.path();
+        if max_depth.is_some_and(|max| depth >= max) {
+            log::debug!("skip {subdirectory:?} deeper than maximum depth");
+            continue;
+        }
+        match fs::read_dir(&subdirectory) {
+            Ok(d) => walk_roots(&subdirectory, d, depth + 1, max_depth, f)?,
+            Err(e) => log::warn!("failed to open directory {subdirectory:?}, skip it: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Directories holding angrr state: the system one and the one of the current user
+///
+/// Root only uses the system one, `HOME` may still be the one of the user invoking sudo.
+fn state_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from("/var/lib/angrr")];
+    if uzers::get_current_uid() == 0 {
+        return dirs;
+    }
+    let user_state = match std::env::var_os("XDG_STATE_HOME") {
+        Some(d) if !d.is_empty() => Some(PathBuf::from(d)),
+        _ => std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state")),
+    };
+    if let Some(d) = user_state {
+        dirs.push(d.join("angrr"));
+    }
+    dirs
+}
+
+/// Whether `path` is missing or a regular file, which can be replaced by a rename
+fn is_replaceable(path: &Path) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata.file_type().is_file(),
+        Err(e) => e.kind() == io::ErrorKind::NotFound,
+    }
+}
+
+/// Errors of network and flaky filesystems worth retrying
+fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.raw_os_error(),
+        Some(libc::EIO | libc::ESTALE | libc::ETIMEDOUT | libc::ENOTCONN)
+    )
+}
+
+/// Remove `.` and `..` components of an absolute path without accessing the filesystem
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            c => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+fn resolve_user(user: &str) -> anyhow::Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+    uzers::get_user_by_name(user)
+        .map(|u| u.uid())
+        .with_context(|| format!("unknown user {user:?}"))
+}
+
+fn matches_any<P: AsRef<Path>>(regexes: &[Regex], path: P) -> bool {
+    let bytes = path.as_ref().as_os_str().as_bytes();
+    regexes.iter().any(|r| r.is_match(bytes))
+}
+
 fn add_indent(text: &str, indent: usize) -> String {
     let indented_lines: Vec<_> = text
         .lines()
@@ -0,0 +1,74 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use regex::bytes::Regex;
+
+/// A set of paths, used for exclusions, pins and the denylist
+///
+/// Entries starting with `^` are regular expressions matched against the
+/// bytes of a path, other entries are literal paths matching themselves
+/// and everything below them.
+#[derive(Debug, Default)]
+pub struct PathMatcher {
+    prefixes: Vec<PathBuf>,
+    regexes: Vec<Regex>,
+}
+
+impl PathMatcher {
+    pub fn load<P: AsRef<Path>>(&mut self, file: P, delimiter: u8) -> anyhow::Result<()> {
+        let file = file.as_ref();
+        let content =
+            fs::read(file).with_context(|| format!("failed to read path list {file:?}"))?;
+        for entry in content.split(|b| *b == delimiter) {
+            if entry.is_empty() {
+                continue;
+            }
+            if entry.starts_with(b"^") {
+                let pattern = std::str::from_utf8(entry).with_context(|| {
+                    format!("invalid regular expression in path list {file:?}: not UTF-8")
+                })?;
+                self.add_regex(pattern)
+                    .with_context(|| format!("invalid entry in path list {file:?}"))?;
+            } else {
+                self.prefixes.push(PathBuf::from(OsStr::from_bytes(entry)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`PathMatcher::load`], but a missing file is an empty list
+    pub fn load_if_exists<P: AsRef<Path>>(&mut self, file: P, delimiter: u8) -> anyhow::Result<()> {
+        let file = file.as_ref();
+        if file.exists() {
+            log::debug!("loading path list {file:?}");
+            self.load(file, delimiter)?;
+        }
+        Ok(())
+    }
+
+    pub fn add_regex(&mut self, pattern: &str) -> anyhow::Result<()> {
+        let regex = Regex::new(pattern)
+            .with_context(|| format!("failed to compile regular expression {pattern:?}"))?;
+        self.regexes.push(regex);
+        Ok(())
+    }
+
+    pub fn add_glob(&mut self, glob: &str) -> anyhow::Result<()> {
+        self.regexes.push(crate::glob::compile(glob)?);
+        Ok(())
+    }
+
+    pub fn is_match<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = path.as_ref();
+        self.prefixes.iter().any(|p| path.starts_with(p))
+            || self
+                .regexes
+                .iter()
+                .any(|r| r.is_match(path.as_os_str().as_bytes()))
+    }
+}
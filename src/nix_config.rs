@@ -0,0 +1,208 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+const DEFAULT_STORE_DIR: &str = "/nix/store";
+const DEFAULT_STATE_DIR: &str = "/nix/var/nix";
+
+/// Physical location of the store
+///
+/// Taken from `NIX_STORE_DIR`, or the `store` setting of nix.conf, like `local?root=/mnt`.
+pub fn store_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("NIX_STORE_DIR") {
+        return dir.into();
+    }
+    let store = Store::from_config();
+    store.physical(store.store.as_deref().unwrap_or(DEFAULT_STORE_DIR))
+}
+
+/// Physical location of the Nix state directory
+///
+/// Taken from `NIX_STATE_DIR`, or the `store` setting of nix.conf.
+pub fn state_dir() -> PathBuf {
+    if let Some(dir) = env::var_os("NIX_STATE_DIR") {
+        return dir.into();
+    }
+    let store = Store::from_config();
+    store.physical(store.state.as_deref().unwrap_or(DEFAULT_STATE_DIR))
+}
+
+/// Default directory of auto GC roots
+pub fn auto_roots_dir() -> PathBuf {
+    state_dir().join("gcroots/auto")
+}
+
+/// Whether `dir` is one of the directories Nix keeps GC roots or profiles in,
+/// like `gcroots/auto` or `profiles/per-user/<user>`
+pub fn is_nix_directory(dir: &Path) -> bool {
+    let state = state_dir();
+    [state.join("gcroots"), state.join("profiles")]
+        .iter()
+        .filter_map(|base| dir.strip_prefix(base).ok())
+        .any(|relative| {
+            let components: Vec<_> = relative.iter().collect();
+            match components.as_slice() {
+                [] => true,
+                [first] => *first == "auto" || *first == "per-user",
+                [first, _] => *first == "per-user",
+                _ => false,
+            }
+        })
+}
+
+/// Profile directories `nix-collect-garbage` handles for `uid`
+///
+/// All profiles for root, the profiles of the user otherwise, only existing directories are returned.
+pub fn profile_dirs(uid: u32) -> Vec<PathBuf> {
+    let profiles = state_dir().join("profiles");
+    let mut dirs = Vec::new();
+    if uid == 0 {
+        dirs.push(profiles);
+    } else {
+        let user_state = match env::var_os("XDG_STATE_HOME") {
+            Some(d) if !d.is_empty() => Some(PathBuf::from(d)),
+            _ => env::var_os("HOME").map(|h| PathBuf::from(h).join(".local/state")),
+        };
+        dirs.extend(user_state.map(|d| d.join("nix/profiles")));
+        if let Some(user) = uzers::get_user_by_uid(uid) {
+            dirs.push(profiles.join("per-user").join(user.name()));
+        }
+    }
+    dirs.retain(|d| d.is_dir());
+    dirs
+}
+
+/// The profile and number of a generation link `<profile>-<N>-link`
+pub fn generation(link: &Path) -> Option<(PathBuf, u64)> {
+    let name = link.file_name()?.to_str()?;
+    let (profile, number) = name.strip_suffix("-link")?.rsplit_once('-')?;
+    Some((link.with_file_name(profile), number.parse().ok()?))
+}
+
+/// Whether `link` is the generation its profile symlink currently points to
+pub fn is_current_generation(link: &Path) -> bool {
+    let Some((profile, _)) = generation(link) else {
+        return false;
+    };
+    fs::read_link(profile).is_ok_and(|current| current.file_name() == link.file_name())
+}
+
+/// Parameters of a local store URI
+#[derive(Debug, Default)]
+struct Store {
+    root: Option<String>,
+    store: Option<String>,
+    state: Option<String>,
+}
+
+impl Store {
+    fn from_config() -> Self {
+        match setting("store") {
+            Some(uri) => Self::parse(&uri),
+            None => Self::default(),
+        }
+    }
+
+    fn parse(uri: &str) -> Self {
+        let (location, query) = uri.split_once('?').unwrap_or((uri, ""));
+        let mut store = Self::default();
+        if location.starts_with('/') {
+            store.root = Some(location.to_owned());
+        } else if location != "local" {
+            // remote stores and the daemon use the default locations
+            return store;
+        }
+        for parameter in query.split('&') {
+            match parameter.split_once('=') {
+                Some(("root", v)) => store.root = Some(v.to_owned()),
+                Some(("store", v)) => store.store = Some(v.to_owned()),
+                Some(("state", v)) => store.state = Some(v.to_owned()),
+                _ => (),
+            }
+        }
+        store
+    }
+
+    fn physical(&self, logical: &str) -> PathBuf {
+        match &self.root {
+            Some(root) => Path::new(root).join(logical.trim_start_matches('/')),
+            None => PathBuf::from(logical),
+        }
+    }
+}
+
+/// The `keep-outputs` setting, derivations keep their outputs alive if enabled
+pub fn keep_outputs() -> bool {
+    bool_setting("keep-outputs", false)
+}
+
+/// The `keep-derivations` setting, outputs keep their derivations alive if enabled
+pub fn keep_derivations() -> bool {
+    bool_setting("keep-derivations", true)
+}
+
+fn bool_setting(name: &str, default: bool) -> bool {
+    match setting(name).as_deref() {
+        Some("true") => true,
+        Some("false") => false,
+        Some(value) => {
+            log::warn!("invalid value {value:?} of setting {name}, use default {default}");
+            default
+        }
+        None => default,
+    }
+}
+
+/// The last value of `name` in nix.conf files and `NIX_CONFIG`
+fn setting(name: &str) -> Option<String> {
+    let conf_dir = env::var_os("NIX_CONF_DIR").unwrap_or_else(|| "/etc/nix".into());
+    let mut files = vec![Path::new(&conf_dir).join("nix.conf")];
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".config")));
+    if let Some(config_home) = config_home {
+        files.push(config_home.join("nix/nix.conf"));
+    }
+    let mut contents: Vec<String> = files
+        .iter()
+        .filter_map(|f| fs::read_to_string(f).ok())
+        .collect();
+    contents.extend(env::var("NIX_CONFIG").ok());
+    let mut value = None;
+    for line in contents.iter().flat_map(|c| c.lines()) {
+        let line = line.split('#').next().unwrap_or_default();
+        if let Some((key, v)) = line.split_once('=') {
+            if key.trim() == name {
+                value = Some(v.trim().to_owned());
+            }
+        }
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generation_links() {
+        assert_eq!(
+            generation(Path::new("/nix/var/nix/profiles/system-42-link")),
+            Some((PathBuf::from("/nix/var/nix/profiles/system"), 42))
+        );
+        assert_eq!(
+            generation(Path::new("profiles/home-manager-1-link")),
+            Some((PathBuf::from("profiles/home-manager"), 1))
+        );
+    }
+
+    #[test]
+    fn not_generation_links() {
+        assert_eq!(generation(Path::new("profiles/system")), None);
+        assert_eq!(generation(Path::new("profiles/system-link")), None);
+        assert_eq!(generation(Path::new("profiles/system-x-link")), None);
+        assert_eq!(generation(Path::new("profiles/system-1")), None);
+        assert_eq!(generation(Path::new("profiles/system-1-link.bak")), None);
+    }
+}
@@ -1,6 +1,8 @@
-use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use clap::{ArgAction, Args, Parser, Subcommand, ValueEnum};
 
-use std::{ffi::OsString, path::PathBuf, time::Duration};
+use std::{ffi::OsString, ops::RangeInclusive, os::fd::RawFd, path::PathBuf, time::Duration};
+
+use crate::nix_config;
 
 const HELP_TEMPLATE: &str = "\
 {before-help}{name} {version}
@@ -26,7 +28,17 @@ pub struct Options {
 
 #[derive(Clone, Debug, Subcommand)]
 pub enum Commands {
-    Run(RunOptions),
+    Run(Box<RunOptions>),
+    Match(Box<MatchOptions>),
+    List(Box<ListOptions>),
+    Explain(Box<ExplainOptions>),
+    Doctor(Box<DoctorOptions>),
+    Status(StatusOptions),
+    Discover(DiscoverOptions),
+    Du(DuOptions),
+    Touch(TouchOptions),
+    ShellHook(ShellHookOptions),
+    SelfTest(SelfTestOptions),
     Completion(CompletionOptions),
 }
 
@@ -34,17 +46,8 @@ pub enum Commands {
 #[command(about = "Do retention")]
 #[command(arg_required_else_help = true)]
 pub struct RunOptions {
-    #[arg(
-        short,
-        long,
-        value_name = "PATH",
-        default_value = "/nix/var/nix/gcroots/auto",
-        help = "directories containing auto GC roots"
-    )]
-    pub directory: Vec<PathBuf>,
-    #[arg(short, long,
-        value_name = "DURATION", value_parser = humantime::parse_duration, help = "retention period")]
-    pub period: Duration,
+    #[command(flatten)]
+    pub selection: SelectionOptions,
     #[arg(
         short,
         long,
@@ -63,39 +66,45 @@ prompt according to WHEN: never, once, or always
     #[arg(short, long, help = "never prompt, override by --interactive")]
     pub no_prompt: bool,
     #[arg(
-        short,
         long,
+        value_name = "N",
         help = "\
-remove GC root instead of the symbolic link target of the root
-also set `--owned-only=false` as default value of `--owned-only`"
+refuse to remove more than N GC roots in one run,
+ask for confirmation instead when interactive"
     )]
-    pub remove_root: bool,
+    pub max_removals: Option<usize>,
     #[arg(
         long,
-        value_name = "BOOL",
-        // add a new line for default and possible values in help
-        help = "only delete owned symbolic link target of GC roots\n",
-        action = ArgAction::Set,
-        num_args = 0..=1,
-        require_equals = true,
-        default_value = "true",
-        default_value_if("remove_root", "true", "false")
+        value_name = "FRACTION",
+        value_parser = parse_fraction,
+        help = "\
+refuse to remove more than FRACTION of the traversed GC roots in one run,
+e.g. `0.5`, ask for confirmation instead when interactive"
     )]
-    pub owned_only: bool,
+    pub max_removal_fraction: Option<f64>,
     #[arg(
         long,
+        value_name = "SIZE",
+        value_parser = parse_size,
         help = "\
-force delete targets of GC roots that do not point to store
-validation only happens when `--remove-root` is not specified"
+only remove expired GC roots while the store filesystem has less than SIZE free,
+oldest first, until their estimated closure sizes make up the difference"
     )]
-    pub force: bool,
+    pub free_until: Option<u64>,
     #[arg(
         long,
-        value_name = "PATH",
-        help = "store path for validation",
-        default_value = "/nix/store"
+        value_name = "PERCENT",
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        help = "like `--free-until`, but with a target of PERCENT of the store filesystem"
+    )]
+    pub min_free_percent: Option<u8>,
+    #[arg(
+        long,
+        help = "\
+force delete GC roots or targets that do not point to store,
+validation happens in all removal modes right before removal"
     )]
-    pub store: PathBuf,
+    pub force: bool,
     #[arg(long, help = "do not output statistic data")]
     pub no_statistic: bool,
     #[arg(
@@ -106,6 +115,14 @@ output removed paths to file,
 when FILE is -, write to standard output"
     )]
     pub output: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "\
+append to the output file instead of replacing it,
+without this option a regular output file is replaced atomically when the run finishes,
+pipes, devices and symbolic links are written through"
+    )]
+    pub output_append: bool,
     #[arg(long, help = "disable extra output buffer")]
     pub output_unbuffered: bool,
     #[arg(
@@ -118,10 +135,469 @@ when FILE is -, write to standard output"
     pub output_delimiter: OsString,
     #[arg(long, help = "use \"\\0\" as the output delimiter")]
     pub null_output_delimiter: bool,
+    #[arg(
+        long,
+        value_name = "FD",
+        help = "\
+write machine-readable progress records to file descriptor FD,
+one tab separated record per line: phase, traversed, candidate, removed, item"
+    )]
+    pub progress_fd: Option<RawFd>,
+    #[arg(
+        long,
+        help = "\
+remove temporary roots of dead processes from the Nix state directory,
+like the garbage collector of Nix does"
+    )]
+    pub clean_temproots: bool,
+    #[arg(
+        long,
+        help = "\
+delete closures of removed GC roots with `nix store delete --skip-live` after the run,
+instead of waiting for the next garbage collection"
+    )]
+    pub delete_closure: bool,
     #[arg(long)]
     pub dry_run: bool,
 }
 
+/// Options deciding which GC roots are expired, shared by `run` and the commands inspecting it
+#[derive(Clone, Debug, Args)]
+pub struct SelectionOptions {
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        default_values_os_t = [nix_config::auto_roots_dir()],
+        help = "\
+directories containing auto GC roots,
+globs like `/home/*/.cache/nix/gcroots` are expanded at start,
+pass `/nix/var/nix/gcroots/per-user` explicitly to also scan per-user GC roots"
+    )]
+    pub directory: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "\
+descend at most N levels of directories to find GC roots,
+1 means only GC roots directly in the directories, unlimited by default,
+subdirectories of GC root links emptied by removal are removed too,
+except the directories of Nix itself like `gcroots/auto`"
+    )]
+    pub max_depth: Option<usize>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "\
+process the GC root PATH instead of scanning directories,
+can be given multiple times"
+    )]
+    pub root: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "\
+process GC roots listed in FILE instead of scanning directories,
+one path per line, `-` reads from standard input"
+    )]
+    pub roots_from: Vec<PathBuf>,
+    #[arg(long, help = "use \"\\0\" as the delimiter of root files")]
+    pub null_roots_delimiter: bool,
+    #[arg(short, long,
+        value_name = "DURATION", value_parser = humantime::parse_duration, help = "retention period")]
+    pub period: Duration,
+    #[arg(
+        long,
+        value_name = "USER=DURATION",
+        value_parser = parse_period_override,
+        help = "\
+use DURATION as the retention period of GC roots whose target is owned by USER,
+USER is a user name or a numeric uid, can be given multiple times"
+    )]
+    pub period_override: Vec<(String, Duration)>,
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+        help = "\
+never remove GC roots younger than DURATION,
+even if they are expired by the denylist or `--expire-superseded`"
+    )]
+    pub min_age: Option<Duration>,
+    #[arg(
+        long,
+        value_name = "SOURCE",
+        help = "timestamp the age of GC roots is computed from",
+        default_value = "mtime"
+    )]
+    pub age_source: AgeSource,
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "\
+only consider GC roots whose symbolic link target matches GLOB,
+`*` does not match `/`, `**` matches any number of directories"
+    )]
+    pub path_glob: Vec<String>,
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "only consider GC roots whose own path matches REGEX"
+    )]
+    pub link_path_regex: Vec<String>,
+    #[arg(
+        long,
+        value_name = "KIND",
+        help = "only consider GC roots resolving to store paths of KIND"
+    )]
+    pub store_path_kind: Option<StorePathKind>,
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "\
+only consider GC roots resolving to store paths whose name matches REGEX,
+the name is the part after the hash, e.g. `python3-3.11.9`"
+    )]
+    pub store_name_regex: Option<String>,
+    #[arg(
+        long,
+        value_name = "SIZE",
+        value_parser = parse_size,
+        help = "\
+only remove roots whose closure is at least SIZE, e.g. `2G` or `500MiB`,
+closure sizes are queried with nix-store"
+    )]
+    pub min_closure_size: Option<u64>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "always keep the newest N roots of each group regardless of age"
+    )]
+    pub keep_latest_n: Option<usize>,
+    #[arg(
+        long,
+        value_name = "GROUP",
+        help = "how roots are grouped for `--keep-latest-n`",
+        default_value = "parent-dir"
+    )]
+    pub group_by: GroupBy,
+    #[arg(
+        long,
+        requires = "keep_latest_n",
+        help = "expire grouped roots other than the newest N regardless of age"
+    )]
+    pub expire_superseded: bool,
+    #[arg(
+        short,
+        long,
+        help = "\
+remove GC root instead of the symbolic link target of the root, same as `--remove=root`
+also set `--owned-only=false` as default value of `--owned-only`"
+    )]
+    pub remove_root: bool,
+    #[arg(
+        long,
+        value_name = "WHAT",
+        help = "\
+what to remove for expired GC roots: the symbolic link target, the root, or both
+`--remove=root` also sets `--owned-only=false` as default value of `--owned-only`
+", // add a new line for default and possible values in help
+        default_value = "target",
+        default_value_if("remove_root", "true", "root")
+    )]
+    pub remove: RemoveMode,
+    #[arg(
+        long,
+        value_name = "BOOL",
+        // add a new line for default and possible values in help
+        help = "only delete owned symbolic link target of GC roots\n",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_value = "true",
+        default_value_ifs([("remove_root", "true", "false"), ("remove", "root", "false")])
+    )]
+    pub owned_only: bool,
+    #[arg(
+        long,
+        value_name = "USER",
+        help = "\
+only consider GC roots whose symbolic link target is owned by USER,
+USER is a user name or a numeric uid, can be given multiple times"
+    )]
+    pub owner: Vec<String>,
+    #[arg(
+        long,
+        value_name = "MIN-MAX",
+        value_parser = parse_uid_range,
+        help = "only consider GC roots whose symbolic link target is owned by a uid in MIN-MAX"
+    )]
+    pub owner_uid_range: Option<RangeInclusive<u32>>,
+    #[arg(
+        long,
+        help = "\
+do not honor `.angrr-keep` marker files,
+by default a marker in any ancestor directory of a target pins the GC root,
+a marker may contain a duration, then it only pins for that long after its last modification"
+    )]
+    pub no_keep_marker: bool,
+    #[arg(
+        long,
+        help = "remove GC roots whose symbolic link target no longer exists"
+    )]
+    pub clean_dangling: bool,
+    #[arg(
+        long,
+        help = "\
+keep GC roots whose target closure is used by running processes,
+found in executables, open files, memory maps and environments in `/proc`,
+and GC roots of projects with live direnv sessions"
+    )]
+    pub keep_in_use: bool,
+    #[arg(
+        long,
+        help = "\
+also accept targets resolving to paths looking like store paths `<hash>-<name>`
+for validation, for stores reached through bind mounts"
+    )]
+    pub validate_store_pattern: bool,
+    #[arg(
+        long,
+        help = "\
+retry canonicalization of targets failing with EIO, ESTALE, ETIMEDOUT or ENOTCONN,
+then follow their symbolic links lexically until a store is reached,
+for flaky network filesystems"
+    )]
+    pub lexical_fallback: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "store path for validation, can be given multiple times",
+        default_values_os_t = [nix_config::store_dir()]
+    )]
+    pub store: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "\
+exclude paths listed in FILE from this run, one entry per line,
+entries starting with `^` are regular expressions,
+other entries exclude the path itself and everything below it,
+both GC roots and their symbolic link targets are checked"
+    )]
+    pub exclude_from: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "REGEX",
+        help = "exclude GC roots or targets matching REGEX from this run"
+    )]
+    pub exclude_regex: Vec<String>,
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "exclude GC roots or targets matching GLOB from this run"
+    )]
+    pub exclude_glob: Vec<String>,
+    #[arg(long, help = "use \"\\0\" as the delimiter of exclude files")]
+    pub null_exclude_delimiter: bool,
+    #[arg(
+        long,
+        conflicts_with_all = ["directory", "root", "roots_from", "remove", "remove_root", "age_source"],
+        help = "\
+mimic `nix-collect-garbage --delete-older-than PERIOD`,
+remove profile generations older than the retention period except the current ones,
+of all profiles as root or of the current user otherwise,
+the store itself is not collected, see `--delete-closure`"
+    )]
+    pub nix_collect_garbage_compat: bool,
+    #[arg(
+        long,
+        help = "\
+also remove GC roots of the derivation of an expired output if keep-outputs is enabled,
+these roots keep the output alive otherwise, output roots are never removed this way"
+    )]
+    pub prune_derivation_roots: bool,
+}
+
+impl From<SelectionOptions> for RunOptions {
+    /// Options of a run only deciding on GC roots, which never prompts nor removes anything
+    fn from(selection: SelectionOptions) -> Self {
+        RunOptions {
+            selection,
+            interactive: Interactive::Never,
+            no_prompt: true,
+            max_removals: None,
+            max_removal_fraction: None,
+            free_until: None,
+            min_free_percent: None,
+            force: false,
+            no_statistic: true,
+            output: None,
+            output_append: false,
+            output_unbuffered: false,
+            output_delimiter: "\n".into(),
+            null_output_delimiter: false,
+            progress_fd: None,
+            clean_temproots: false,
+            delete_closure: false,
+            dry_run: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "Show how `run` would handle a GC root or a target")]
+#[command(arg_required_else_help = true)]
+pub struct MatchOptions {
+    #[arg(
+        value_name = "PATH",
+        help = "GC root, or symbolic link target of GC roots"
+    )]
+    pub path: PathBuf,
+    #[command(flatten)]
+    pub selection: SelectionOptions,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "Trace every step of the decision of `run` on a GC root or a target")]
+#[command(arg_required_else_help = true)]
+pub struct ExplainOptions {
+    #[arg(
+        value_name = "PATH",
+        help = "GC root, or symbolic link target of GC roots"
+    )]
+    pub path: PathBuf,
+    #[command(flatten)]
+    pub selection: SelectionOptions,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "Check the environment `run` would work in with the same options")]
+#[command(arg_required_else_help = true)]
+pub struct DoctorOptions {
+    #[command(flatten)]
+    pub run: RunOptions,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "Show the outcome of the last run")]
+pub struct StatusOptions {
+    #[arg(
+        long,
+        value_name = "UNIT",
+        default_value = "angrr.timer",
+        help = "systemd timer asked for the time of the next run"
+    )]
+    pub timer: String,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "List scanned GC roots and how `run` would handle them")]
+#[command(arg_required_else_help = true)]
+pub struct ListOptions {
+    #[arg(long, value_name = "FORMAT", default_value = "table")]
+    pub format: ListFormat,
+    #[command(flatten)]
+    pub selection: SelectionOptions,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "Find result and direnv links into the store not registered as GC roots")]
+#[command(arg_required_else_help = true)]
+pub struct DiscoverOptions {
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        default_values_os_t = [nix_config::auto_roots_dir()],
+        help = "directories containing auto GC roots"
+    )]
+    pub directory: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "store path links must point into, can be given multiple times",
+        default_values_os_t = [nix_config::store_dir()]
+    )]
+    pub store: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "N",
+        help = "descend at most N levels of directories in project trees"
+    )]
+    pub max_depth: Option<usize>,
+    #[arg(
+        long,
+        help = "register the links found as indirect GC roots with `nix-store --add-root`"
+    )]
+    pub register: bool,
+    #[arg(
+        value_name = "PATH",
+        required = true,
+        help = "project trees to search, e.g. `~/src`"
+    )]
+    pub path: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "Report closure disk usage of GC roots")]
+pub struct DuOptions {
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        default_values_os_t = [nix_config::auto_roots_dir()],
+        help = "directories containing auto GC roots"
+    )]
+    pub directory: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "store path of GC root targets, can be given multiple times",
+        default_values_os_t = [nix_config::store_dir()]
+    )]
+    pub store: Vec<PathBuf>,
+    #[arg(
+        long,
+        value_name = "GROUP",
+        help = "how roots are aggregated, closures are deduplicated in every group",
+        default_value = "parent-dir"
+    )]
+    pub group_by: GroupBy,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "Refresh modified time of GC roots registered from a project directory")]
+pub struct TouchOptions {
+    #[arg(
+        short,
+        long,
+        value_name = "PATH",
+        default_values_os_t = [nix_config::auto_roots_dir()],
+        help = "directories containing auto GC roots"
+    )]
+    pub directory: Vec<PathBuf>,
+    #[arg(
+        value_name = "PATH",
+        help = "project directory, defaults to the current directory"
+    )]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "Print a shell hook running `angrr touch` on directory change")]
+#[command(arg_required_else_help = true)]
+pub struct ShellHookOptions {
+    pub shell: HookShell,
+}
+
+#[derive(Clone, Debug, Parser)]
+#[command(about = "Run a canned retention scenario in a temporary directory")]
+pub struct SelfTestOptions {
+    #[arg(long, help = "keep the temporary directory for inspection")]
+    pub keep_temp: bool,
+}
+
 #[derive(Clone, Debug, Parser)]
 #[command(about = "Generate shell completions")]
 #[command(arg_required_else_help = true)]
@@ -135,3 +611,159 @@ pub enum Interactive {
     Once,
     Always,
 }
+
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq, Eq)]
+pub enum HookShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq, Eq)]
+pub enum ListFormat {
+    /// aligned columns for humans
+    Table,
+    /// an array of objects, paths are converted lossily to UTF-8,
+    /// `root_bytes` and `target_bytes` hold their exact bytes
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq, Eq)]
+pub enum GroupBy {
+    /// all roots form a single group
+    None,
+    /// group roots by the parent directory of their symbolic link target
+    ParentDir,
+    /// group roots by the package name of their store path, without the version
+    PackageName,
+}
+
+/// Parse sizes like `2G`, `1.5GiB` or `300000`, units are powers of 1024
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size number {number:?}"))?;
+    let exponent = match unit.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 1,
+        "M" | "MB" | "MIB" => 2,
+        "G" | "GB" | "GIB" => 3,
+        "T" | "TB" | "TIB" => 4,
+        u => return Err(format!("unknown size unit {u:?}")),
+    };
+    Ok((number * 1024f64.powi(exponent)) as u64)
+}
+
+/// Parse fractions between 0 and 1
+pub fn parse_fraction(s: &str) -> Result<f64, String> {
+    let fraction: f64 = s
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid fraction {s:?}"))?;
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(format!("fraction {s:?} is not between 0 and 1"));
+    }
+    Ok(fraction)
+}
+
+/// Parse `USER=DURATION` pairs
+pub fn parse_period_override(s: &str) -> Result<(String, Duration), String> {
+    let (user, period) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid period override {s:?}, expecting USER=DURATION"))?;
+    let period =
+        humantime::parse_duration(period).map_err(|e| format!("invalid period {period:?}: {e}"))?;
+    Ok((user.to_string(), period))
+}
+
+/// Parse inclusive uid ranges like `1000-1999`
+pub fn parse_uid_range(s: &str) -> Result<RangeInclusive<u32>, String> {
+    let (min, max) = s
+        .split_once('-')
+        .ok_or_else(|| format!("invalid uid range {s:?}, expecting MIN-MAX"))?;
+    let parse = |n: &str| {
+        n.trim()
+            .parse::<u32>()
+            .map_err(|e| format!("invalid uid {n:?}: {e}"))
+    };
+    let (min, max) = (parse(min)?, parse(max)?);
+    if min > max {
+        return Err(format!(
+            "invalid uid range {s:?}, {min} is greater than {max}"
+        ));
+    }
+    Ok(min..=max)
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq, Eq)]
+pub enum AgeSource {
+    /// modified time of the symbolic link target
+    Mtime,
+    /// access time of the symbolic link target
+    Atime,
+    /// status change time of the symbolic link target
+    Ctime,
+    /// modified time of the GC root link itself
+    LinkMtime,
+    /// the latest of all timestamps above
+    Max,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq, Eq)]
+pub enum RemoveMode {
+    Target,
+    Root,
+    Both,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum, PartialEq, Eq)]
+pub enum StorePathKind {
+    /// derivations, store paths ending with `.drv`
+    Drv,
+    /// all other store paths
+    Output,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_consistent() {
+        use clap::CommandFactory;
+        Options::command().debug_assert();
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("1K"), Ok(1024));
+        assert_eq!(parse_size("2mb"), Ok(2 * 1024 * 1024));
+        assert_eq!(parse_size("1.5 GiB"), Ok(3 * 512 * 1024 * 1024));
+        assert_eq!(parse_size(" 1T "), Ok(1 << 40));
+        assert_eq!(parse_size("0"), Ok(0));
+    }
+
+    #[test]
+    fn uid_ranges() {
+        assert_eq!(parse_uid_range("1000-60000"), Ok(1000..=60000));
+        assert_eq!(parse_uid_range("0-0"), Ok(0..=0));
+        assert!(parse_uid_range("1000").is_err());
+        assert!(parse_uid_range("x-1000").is_err());
+        assert!(parse_uid_range("60000-1000").is_err());
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("1Q").is_err());
+        assert!(parse_size("1.2.3M").is_err());
+        assert!(parse_size("-1K").is_err());
+    }
+}
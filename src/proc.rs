@@ -0,0 +1,154 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{self, OpenOptions},
+    io,
+    os::{fd::AsRawFd, unix::ffi::OsStrExt},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::Context;
+use regex::bytes::Regex;
+
+/// What running processes are using, found in `/proc`
+#[derive(Debug, Default)]
+pub struct Runtime {
+    /// top-level store paths in use, and a pid using each of them
+    pub paths: HashMap<PathBuf, u32>,
+    /// project directories of live direnv sessions, and a pid of each session
+    pub sessions: HashMap<PathBuf, u32>,
+}
+
+/// Scan all running processes
+///
+/// Like the runtime roots of the Nix garbage collector, store paths are collected
+/// from executables, working and root directories, open files, memory maps and environments,
+/// processes that cannot be inspected are skipped.
+/// Direnv sessions are detected by `DIRENV_DIR` in environments and open files in `.direnv`.
+pub fn scan(stores: &[PathBuf]) -> anyhow::Result<Runtime> {
+    let stores: Vec<_> = stores
+        .iter()
+        .map(|s| regex::escape(&s.to_string_lossy()))
+        .collect();
+    let pattern = format!(
+        r"(?:{})/[0-9a-z]{{32}}-[0-9a-zA-Z+\-._?=]+",
+        stores.join("|")
+    );
+    let regex = Regex::new(&pattern)
+        .with_context(|| format!("failed to compile regular expression {pattern:?}"))?;
+    let mut runtime = Runtime::default();
+    let entries = fs::read_dir("/proc").context("failed to read directory \"/proc\"")?;
+    for entry in entries {
+        let entry = entry.context("failed to read directory \"/proc\"")?;
+        let Some(pid) = entry
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let process = Process::read(&entry.path());
+        for m in process.data().flat_map(|d| regex.find_iter(d)) {
+            let path = PathBuf::from(OsStr::from_bytes(m.as_bytes()));
+            runtime.paths.entry(path).or_insert(pid);
+        }
+        for dir in process.direnv_dirs() {
+            runtime.sessions.entry(dir).or_insert(pid);
+        }
+    }
+    log::debug!(
+        "{} store paths and {} direnv sessions in use by running processes",
+        runtime.paths.len(),
+        runtime.sessions.len()
+    );
+    Ok(runtime)
+}
+
+/// Whether a file of temporary roots belongs to a dead process, and remove it if so
+///
+/// Like the garbage collector of Nix, the owner is dead if the file is not locked.
+/// The lock is held while removing so a new process can not reuse the file meanwhile.
+pub fn remove_stale_temproots(path: &Path, dry_run: bool) -> io::Result<bool> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    // SAFETY: the file descriptor is valid until `file` is dropped
+    let locked = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if locked != 0 {
+        let error = io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(libc::EWOULDBLOCK) => Ok(false),
+            _ => Err(error),
+        };
+    }
+    if !dry_run {
+        fs::remove_file(path)?;
+    }
+    Ok(true)
+}
+
+/// Everything of a process possibly mentioning store paths
+#[derive(Debug, Default)]
+struct Process {
+    /// targets of `exe`, `cwd`, `root` and open file descriptors
+    links: Vec<PathBuf>,
+    maps: Vec<u8>,
+    environ: Vec<u8>,
+}
+
+impl Process {
+    fn read(dir: &Path) -> Self {
+        let mut process = Self::default();
+        for link in ["exe", "cwd", "root"] {
+            if let Ok(target) = fs::read_link(dir.join(link)) {
+                process.links.push(target);
+            }
+        }
+        if let Ok(fds) = fs::read_dir(dir.join("fd")) {
+            for fd in fds.flatten() {
+                if let Ok(target) = fs::read_link(fd.path()) {
+                    process.links.push(target);
+                }
+            }
+        }
+        for (file, content) in [
+            ("maps", &mut process.maps),
+            ("environ", &mut process.environ),
+        ] {
+            match fs::read(dir.join(file)) {
+                Ok(c) => *content = c,
+                Err(e) => log::trace!("skip {:?}: {e}", dir.join(file)),
+            }
+        }
+        process
+    }
+
+    fn data(&self) -> impl Iterator<Item = &[u8]> {
+        self.links
+            .iter()
+            .map(|l| l.as_os_str().as_bytes())
+            .chain([self.maps.as_slice(), self.environ.as_slice()])
+    }
+
+    fn direnv_dirs(&self) -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+        for variable in self.environ.split(|b| *b == 0) {
+            if let Some(value) = variable.strip_prefix(b"DIRENV_DIR=") {
+                // direnv prefixes the directory with `-`
+                let value = value.strip_prefix(b"-").unwrap_or(value);
+                dirs.push(PathBuf::from(OsStr::from_bytes(value)));
+            }
+        }
+        for link in &self.links {
+            let mut dir = PathBuf::new();
+            for component in link.components() {
+                if component == Component::Normal(OsStr::new(".direnv")) {
+                    dirs.push(dir);
+                    break;
+                }
+                dir.push(component);
+            }
+        }
+        // a session of `/` or a relative directory would protect everything
+        dirs.retain(|d| d.is_absolute() && d.parent().is_some());
+        dirs
+    }
+}
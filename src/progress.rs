@@ -0,0 +1,97 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Write},
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::Path,
+};
+
+use anyhow::Context;
+
+/// Machine-readable progress records
+///
+/// Every record is a single line of tab separated fields:
+/// `<phase> <traversed> <candidate> <removed> <item>`,
+/// the item is written as raw bytes and is empty when not applicable.
+#[derive(Debug)]
+pub struct Progress {
+    file: Option<File>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    Scan,
+    Remove,
+    Done,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Counts {
+    pub traversed: usize,
+    pub candidate: usize,
+    pub removed: usize,
+}
+
+impl Progress {
+    /// Write records to a duplicate of `fd`, which stays open, even if it is stdout or stderr
+    pub fn new(fd: Option<RawFd>) -> anyhow::Result<Self> {
+        let file = match fd {
+            Some(fd) => Some(
+                duplicate(fd).with_context(|| format!("invalid progress file descriptor {fd}"))?,
+            ),
+            None => None,
+        };
+        Ok(Self { file })
+    }
+
+    pub fn report(
+        &mut self,
+        phase: Phase,
+        counts: Counts,
+        item: Option<&Path>,
+    ) -> anyhow::Result<()> {
+        if let Some(file) = &mut self.file {
+            let Counts {
+                traversed,
+                candidate,
+                removed,
+            } = counts;
+            let mut record = format!("{phase}\t{traversed}\t{candidate}\t{removed}\t").into_bytes();
+            if let Some(item) = item {
+                record.extend_from_slice(item.as_os_str().as_bytes());
+            }
+            record.push(b'\n');
+            file.write_all(&record)
+                .context("failed to write progress record")?;
+        }
+        Ok(())
+    }
+}
+
+fn duplicate(fd: RawFd) -> io::Result<File> {
+    // SAFETY: `F_GETFD` only queries the descriptor flags, an invalid `fd` fails with `EBADF`
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is open, the duplicate is a new descriptor owned by nothing else
+    let duplicated = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+    if duplicated < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `duplicated` was just created and is only owned by the returned file
+    Ok(unsafe { File::from_raw_fd(duplicated) })
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Phase::Scan => "scan",
+            Phase::Remove => "remove",
+            Phase::Done => "done",
+        };
+        f.write_str(s)
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    fs,
+    io::Write,
+    iter,
+    os::unix::fs::symlink,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use console::Term;
+
+use crate::{
+    options::{RunOptions, SelfTestOptions},
+    touch::set_symlink_mtime,
+    RunContext,
+};
+
+const FAKE_HASH: &str = "00000000000000000000000000000000";
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+struct Case {
+    name: &'static str,
+    age: Duration,
+    target: Target,
+    expect_removed: bool,
+}
+
+enum Target {
+    Store,
+    Outside,
+    Missing,
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "expired",
+        age: Duration::from_secs(30 * DAY.as_secs()),
+        target: Target::Store,
+        expect_removed: true,
+    },
+    Case {
+        name: "fresh",
+        age: DAY,
+        target: Target::Store,
+        expect_removed: false,
+    },
+    Case {
+        name: "outside-store",
+        age: Duration::from_secs(30 * DAY.as_secs()),
+        target: Target::Outside,
+        expect_removed: false,
+    },
+    Case {
+        name: "dangling",
+        age: Duration::from_secs(30 * DAY.as_secs()),
+        target: Target::Missing,
+        expect_removed: false,
+    },
+];
+
+/// Generations of a fake profile, all older than the period, the last one is current
+const GENERATIONS: &[(u64, bool)] = &[(1, true), (2, true), (3, false)];
+
+pub fn self_test(options: SelfTestOptions) -> anyhow::Result<()> {
+    let temp_dir = tempfile::Builder::new()
+        .prefix("angrr-self-test-")
+        .tempdir()
+        .context("failed to create temporary directory")?;
+    let base = temp_dir.path();
+    let store = base.join("store");
+    let outside = base.join("outside");
+    let projects = base.join("projects");
+    let auto = base.join("gcroots/auto");
+    let profiles = base.join("profiles");
+    // pins and denylist of the host must not change the outcome
+    let state = base.join("state");
+    for dir in [&store, &outside, &projects, &auto, &profiles, &state] {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {dir:?}"))?;
+    }
+
+    let now = SystemTime::now();
+    for case in CASES {
+        let name = case.name;
+        let store_path = match case.target {
+            Target::Store | Target::Missing => store.join(format!("{FAKE_HASH}-{name}")),
+            Target::Outside => outside.join(name),
+        };
+        if !matches!(case.target, Target::Missing) {
+            fs::write(&store_path, name)
+                .with_context(|| format!("failed to create {store_path:?}"))?;
+        }
+        let project_link = projects.join(format!("{name}-result"));
+        symlink(&store_path, &project_link)
+            .with_context(|| format!("failed to create {project_link:?}"))?;
+        set_symlink_mtime(&project_link, now - case.age)
+            .with_context(|| format!("failed to set modified time of {project_link:?}"))?;
+        if matches!(case.target, Target::Missing) {
+            fs::remove_file(&project_link)
+                .with_context(|| format!("failed to remove {project_link:?}"))?;
+        }
+        let root = auto.join(name);
+        symlink(&project_link, &root).with_context(|| format!("failed to create {root:?}"))?;
+    }
+    for (number, _) in GENERATIONS {
+        let store_path = store.join(format!("{FAKE_HASH}-profile-{number}"));
+        fs::write(&store_path, "profile")
+            .with_context(|| format!("failed to create {store_path:?}"))?;
+        let generation = profiles.join(format!("profile-{number}-link"));
+        symlink(&store_path, &generation)
+            .with_context(|| format!("failed to create {generation:?}"))?;
+        set_symlink_mtime(&generation, now - 30 * DAY)
+            .with_context(|| format!("failed to set modified time of {generation:?}"))?;
+    }
+    if let Some((current, _)) = GENERATIONS.last() {
+        let profile = profiles.join("profile");
+        symlink(format!("profile-{current}-link"), &profile)
+            .with_context(|| format!("failed to create {profile:?}"))?;
+    }
+
+    let common = [
+        "--store".as_ref(),
+        store.as_os_str(),
+        "--period".as_ref(),
+        "7d".as_ref(),
+        "--no-prompt".as_ref(),
+        "--owned-only=false".as_ref(),
+        "--no-statistic".as_ref(),
+    ];
+    let roots = ["--directory".as_ref(), auto.as_os_str()];
+    // generation links are removed themselves, aged by their own modified time
+    let generations = [
+        "--directory".as_ref(),
+        profiles.as_os_str(),
+        "--remove".as_ref(),
+        "root".as_ref(),
+        "--age-source".as_ref(),
+        "link-mtime".as_ref(),
+    ];
+    for arguments in [&roots[..], &generations[..]] {
+        let run_options = RunOptions::try_parse_from(
+            iter::once("run".as_ref())
+                .chain(common)
+                .chain(arguments.iter().copied()),
+        )
+        .context("failed to build run options")?;
+        let context = RunContext::with_state_dirs(run_options, std::slice::from_ref(&state))?;
+        context.run()?;
+        context.finish()?;
+    }
+
+    let mut term = Term::stderr();
+    let mut failures = 0;
+    for case in CASES {
+        // dangling roots have no target, check that the root itself is kept
+        let checked = match case.target {
+            Target::Missing => auto.join(case.name),
+            _ => projects.join(format!("{}-result", case.name)),
+        };
+        if !report(&mut term, case.name, &checked, case.expect_removed)? {
+            failures += 1;
+        }
+    }
+    for (number, expect_removed) in GENERATIONS {
+        let generation = profiles.join(format!("profile-{number}-link"));
+        let name = format!("generation-{number}");
+        if !report(&mut term, &name, &generation, *expect_removed)? {
+            failures += 1;
+        }
+    }
+
+    if options.keep_temp {
+        let kept = temp_dir.into_path();
+        writeln!(term, "temporary directory kept at {kept:?}")?;
+    }
+    if failures != 0 {
+        anyhow::bail!("self test failed, {failures} case(s) mismatched");
+    }
+    Ok(())
+}
+
+/// Print whether `path` was removed as expected, returns whether it was
+fn report(term: &mut Term, name: &str, path: &Path, expected: bool) -> anyhow::Result<bool> {
+    let removed = !exists_no_follow(path);
+    let (status, style) = if removed == expected {
+        ("ok", term.style().green().bold())
+    } else {
+        ("FAILED", term.style().red().bold())
+    };
+    writeln!(
+        term,
+        "{} {name} (expected {}, got {})",
+        style.apply_to(status),
+        removal_word(expected),
+        removal_word(removed)
+    )?;
+    Ok(removed == expected)
+}
+
+fn exists_no_follow(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok()
+}
+
+fn removal_word(removed: bool) -> &'static str {
+    if removed {
+        "removed"
+    } else {
+        "kept"
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use console::Term;
+use humantime::{format_duration, format_rfc3339_seconds};
+
+use crate::{options::StatusOptions, state_dirs, store};
+
+const STATUS_FILE: &str = "last-run";
+
+/// Summary of a run, kept in the state directory for `status`
+#[derive(Debug, Default)]
+pub struct RunRecord {
+    pub time: u64,
+    pub dry_run: bool,
+    /// error of a failed run
+    pub error: Option<String>,
+    pub traversed: usize,
+    pub candidate: usize,
+    pub removed: usize,
+    pub dangling: usize,
+    pub censored: usize,
+    /// estimated freed space, only known with `--free-until` or `--min-free-percent`
+    pub freed: Option<u64>,
+}
+
+impl RunRecord {
+    /// A run failed before scanning anything
+    pub fn failed(dry_run: bool, error: &anyhow::Error) -> Self {
+        Self {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            dry_run,
+            error: Some(format!("{error:#}")),
+            ..Default::default()
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!(
+            "time={}\ndry_run={}\ntraversed={}\ncandidate={}\nremoved={}\ndangling={}\ncensored={}\n",
+            self.time,
+            self.dry_run,
+            self.traversed,
+            self.candidate,
+            self.removed,
+            self.dangling,
+            self.censored
+        );
+        if let Some(freed) = self.freed {
+            text.push_str(&format!("freed={freed}\n"));
+        }
+        if let Some(error) = &self.error {
+            // keep the record one line per field
+            text.push_str(&format!("error={}\n", error.replace('\n', " ")));
+        }
+        text
+    }
+
+    fn parse(text: &str) -> Self {
+        let mut record = Self::default();
+        for (key, value) in text.lines().filter_map(|l| l.split_once('=')) {
+            let number = || value.parse().unwrap_or_default();
+            match key {
+                "time" => record.time = value.parse().unwrap_or_default(),
+                "dry_run" => record.dry_run = value == "true",
+                "traversed" => record.traversed = number(),
+                "candidate" => record.candidate = number(),
+                "removed" => record.removed = number(),
+                "dangling" => record.dangling = number(),
+                "censored" => record.censored = number(),
+                "freed" => record.freed = value.parse().ok(),
+                "error" => record.error = Some(value.to_owned()),
+                _ => log::debug!("unknown field {key:?} in run record"),
+            }
+        }
+        record
+    }
+}
+
+/// The state directory of the current user, the system one for root
+fn own_state_dir() -> Option<PathBuf> {
+    state_dirs().pop()
+}
+
+/// Replace the record of the last run, failures are only logged
+pub fn record(record: &RunRecord) {
+    let Some(dir) = own_state_dir() else {
+        return;
+    };
+    if let Err(e) = write_record(&dir, record) {
+        log::warn!("failed to record the run in {dir:?}: {e:#}");
+    }
+}
+
+fn write_record(dir: &Path, record: &RunRecord) -> anyhow::Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create directory {dir:?}"))?;
+    let mut file = tempfile::Builder::new()
+        .prefix(".last-run")
+        .tempfile_in(dir)
+        .context("failed to create temporary file")?;
+    file.write_all(record.to_text().as_bytes())?;
+    file.persist(dir.join(STATUS_FILE))?;
+    Ok(())
+}
+
+pub fn status(options: StatusOptions) -> anyhow::Result<()> {
+    let mut term = Term::stdout();
+    let mut found = false;
+    for dir in state_dirs() {
+        let path = dir.join(STATUS_FILE);
+        let text = match fs::read_to_string(&path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                log::warn!("failed to read {path:?}: {e}");
+                continue;
+            }
+        };
+        found = true;
+        writeln!(
+            term,
+            "{}",
+            term.style().bold().underlined().apply_to(&path.display())
+        )?;
+        term.write_line(&format_record(&term, &RunRecord::parse(&text)))?;
+    }
+    if !found {
+        term.write_line("no run recorded yet")?;
+    }
+    if let Some(next) = next_elapse(&options.timer) {
+        writeln!(term, "next run:  {next} by {}", options.timer)?;
+    }
+    Ok(())
+}
+
+fn format_record(term: &Term, record: &RunRecord) -> String {
+    let time = UNIX_EPOCH + Duration::from_secs(record.time);
+    let ago = SystemTime::now()
+        .duration_since(time)
+        .map(|d| format_duration(Duration::from_secs(d.as_secs())).to_string())
+        .unwrap_or_default();
+    let outcome = match (&record.error, record.dry_run) {
+        (Some(error), _) => term
+            .style()
+            .red()
+            .bold()
+            .apply_to(format!("failed: {error}"))
+            .to_string(),
+        (None, true) => term.style().cyan().bold().apply_to("dry run").to_string(),
+        (None, false) => term
+            .style()
+            .green()
+            .bold()
+            .apply_to("succeeded")
+            .to_string(),
+    };
+    let mut lines = vec![
+        format!("last run:  {} ({ago} ago)", format_rfc3339_seconds(time)),
+        format!("outcome:   {outcome}"),
+        format!(
+            "roots:     {} traversed, {} candidate, {} removed",
+            record.traversed, record.candidate, record.removed
+        ),
+        format!(
+            "skipped:   {} dangling, {} censored",
+            record.dangling, record.censored
+        ),
+    ];
+    if let Some(freed) = record.freed {
+        lines.push(format!("freed:     about {}", store::format_size(freed)));
+    }
+    lines.join("\n")
+}
+
+/// When systemd runs `timer` next, `None` without systemd or the timer
+fn next_elapse(timer: &str) -> Option<String> {
+    let output = Command::new("systemctl")
+        .args([
+            "show",
+            timer,
+            "--property=NextElapseUSecRealtime",
+            "--value",
+        ])
+        .output()
+        .ok()?;
+    let next = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    (output.status.success() && !next.is_empty()).then_some(next)
+}
@@ -0,0 +1,342 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    ffi::{CString, OsStr},
+    io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use anyhow::Context;
+
+/// Bytes of path arguments passed to a single command, well below `ARG_MAX`
+const MAX_ARGS_BYTES: usize = 64 * 1024;
+
+/// The top-level store path containing `path`, `path` must be canonical
+pub fn store_path(store: &Path, path: &Path) -> Option<PathBuf> {
+    let relative = path.strip_prefix(store).ok()?;
+    let first = relative.components().next()?;
+    Some(store.join(first))
+}
+
+/// Whether any component of `path` looks like a store path name `<hash>-<name>`,
+/// the hash being 32 characters of the base-32 alphabet of Nix
+pub fn has_store_path_name(path: &Path) -> bool {
+    const ALPHABET: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+    path.components().any(|c| {
+        let name = c.as_os_str().as_bytes();
+        name.len() > 33 && name[32] == b'-' && name[..32].iter().all(|b| ALPHABET.contains(b))
+    })
+}
+
+/// The name part of a top-level store path, following the hash
+pub fn store_name(store_path: &Path) -> Option<&[u8]> {
+    let file_name = store_path.file_name()?.as_bytes();
+    let dash = file_name.iter().position(|b| *b == b'-')?;
+    Some(&file_name[dash + 1..])
+}
+
+/// The package name of a store path name without its version,
+/// split at the first `-` not followed by a letter like Nix does
+pub fn package_name(name: &[u8]) -> &[u8] {
+    let split = name
+        .windows(2)
+        .position(|w| w[0] == b'-' && !w[1].is_ascii_alphabetic());
+    match split {
+        Some(i) => &name[..i],
+        None => name,
+    }
+}
+
+/// Whether a top-level store path is a derivation
+pub fn is_derivation(store_path: &Path) -> bool {
+    store_path
+        .file_name()
+        .is_some_and(|n| n.as_bytes().ends_with(b".drv"))
+}
+
+/// Sum of the NAR sizes of all paths in the closure of `path`
+pub fn closure_size<P: AsRef<Path>>(path: P) -> anyhow::Result<u64> {
+    total_size(&requisites(path)?)
+}
+
+/// Sum of the NAR sizes of `paths`
+pub fn total_size(paths: &[PathBuf]) -> anyhow::Result<u64> {
+    if paths.is_empty() {
+        return Ok(0);
+    }
+    let output = nix_store_batched(&["--query", "--size"], paths)?;
+    output
+        .split(|b| *b == b'\n')
+        .filter(|l| !l.is_empty())
+        .map(|l| {
+            std::str::from_utf8(l)
+                .ok()
+                .and_then(|s| s.trim().parse::<u64>().ok())
+                .with_context(|| format!("invalid size {:?} from nix-store", OsStr::from_bytes(l)))
+        })
+        .sum()
+}
+
+/// Available and total bytes of the filesystem containing `path`
+pub fn filesystem_space(path: &Path) -> io::Result<(u64, u64)> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `statvfs` is plain old data, filled by the call below
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is a valid nul terminated string and `stat` is a valid buffer
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fragment = stat.f_frsize as u64;
+    Ok((
+        stat.f_bavail as u64 * fragment,
+        stat.f_blocks as u64 * fragment,
+    ))
+}
+
+/// Estimate of the space freed by removing GC roots, accounting for sharing
+///
+/// A path of the removed closures only counts as freed if no path outside them refers to it
+/// and it is not the target of a surviving GC root. Roots outside the scanned directories,
+/// like profiles, are unknown, but usually protect paths through their referrers.
+#[derive(Debug, Default)]
+pub struct FreedSpace {
+    closure: BTreeSet<PathBuf>,
+}
+
+impl FreedSpace {
+    /// Add the closure of a removed GC root
+    pub fn add(&mut self, closure: &[PathBuf]) {
+        self.closure.extend(closure.iter().cloned());
+    }
+
+    /// Sum of the NAR sizes of paths no longer alive, `alive` are targets of surviving roots
+    ///
+    /// Paths referred to from outside the dead set are alive, and so is everything they
+    /// refer to, which is followed reference by reference with one batched query per step.
+    pub fn estimate(&self, alive: &HashSet<PathBuf>) -> anyhow::Result<u64> {
+        let mut dead: BTreeSet<_> = self
+            .closure
+            .iter()
+            .filter(|p| !alive.contains(*p))
+            .cloned()
+            .collect();
+        let dead_paths: Vec<_> = dead.iter().cloned().collect();
+        let mut frontier: Vec<_> = query_all("--referrers", &dead_paths)?
+            .into_iter()
+            .filter(|p| !dead.contains(p))
+            .collect();
+        while !frontier.is_empty() {
+            frontier = query_all("--references", &frontier)?
+                .into_iter()
+                .filter(|p| dead.remove(p))
+                .collect();
+        }
+        total_size(&dead.into_iter().collect::<Vec<_>>())
+    }
+}
+
+/// Format a size in bytes with a binary unit, like `1.5 GiB`
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// Whether `path` is a valid path of the store
+pub fn is_valid<P: AsRef<Path>>(path: P) -> anyhow::Result<bool> {
+    let path = path.as_ref();
+    let output = nix_store([
+        OsStr::new("--check-validity"),
+        OsStr::new("--print-invalid"),
+        path.as_os_str(),
+    ])?;
+    Ok(lines_to_paths(&output).is_empty())
+}
+
+/// Store paths directly referring to `path`
+pub fn referrers<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let output = nix_store([
+        OsStr::new("--query"),
+        OsStr::new("--referrers"),
+        path.as_os_str(),
+    ])?;
+    Ok(lines_to_paths(&output)
+        .into_iter()
+        .filter(|p| p != path)
+        .collect())
+}
+
+/// The derivation `path` was built from, if known
+pub fn deriver<P: AsRef<Path>>(path: P) -> anyhow::Result<Option<PathBuf>> {
+    let output = nix_store([
+        OsStr::new("--query"),
+        OsStr::new("--deriver"),
+        path.as_ref().as_os_str(),
+    ])?;
+    Ok(lines_to_paths(&output)
+        .into_iter()
+        .find(|p| p.is_absolute()))
+}
+
+/// Outputs of the derivation `drv`
+pub fn outputs<P: AsRef<Path>>(drv: P) -> anyhow::Result<Vec<PathBuf>> {
+    let output = nix_store([
+        OsStr::new("--query"),
+        OsStr::new("--outputs"),
+        drv.as_ref().as_os_str(),
+    ])?;
+    Ok(lines_to_paths(&output))
+}
+
+/// Version reported by `nix-store`, also checks it can be run at all
+pub fn version() -> anyhow::Result<String> {
+    let output = nix_store(["--version"])?;
+    Ok(String::from_utf8_lossy(&output).trim().to_owned())
+}
+
+/// All store paths in the closure of `path`
+pub fn requisites<P: AsRef<Path>>(path: P) -> anyhow::Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let output = nix_store([
+        OsStr::new("--query"),
+        OsStr::new("--requisites"),
+        path.as_os_str(),
+    ])?;
+    Ok(lines_to_paths(&output))
+}
+
+/// Register `link` pointing to `store_path` as an indirect GC root
+pub fn add_indirect_root(link: &Path, store_path: &Path) -> anyhow::Result<()> {
+    nix_store([
+        OsStr::new("--add-root"),
+        link.as_os_str(),
+        OsStr::new("--indirect"),
+        OsStr::new("--realise"),
+        store_path.as_os_str(),
+    ])?;
+    Ok(())
+}
+
+fn lines_to_paths(output: &[u8]) -> Vec<PathBuf> {
+    output
+        .split(|b| *b == b'\n')
+        .filter(|l| !l.is_empty())
+        .map(|l| PathBuf::from(OsStr::from_bytes(l)))
+        .collect()
+}
+
+/// Delete `paths` from the store with `nix store delete --skip-live`,
+/// returns the summary printed by Nix
+pub fn delete_skip_live(paths: &[PathBuf]) -> anyhow::Result<String> {
+    let mut summaries = Vec::new();
+    for chunk in chunks(paths) {
+        let mut command = Command::new("nix");
+        command
+            .args([
+                "--extra-experimental-features",
+                "nix-command",
+                "store",
+                "delete",
+                "--skip-live",
+            ])
+            .args(chunk);
+        let output = run(command)?;
+        summaries.push(String::from_utf8_lossy(&output.stderr).trim().to_owned());
+    }
+    Ok(summaries.join("; "))
+}
+
+/// Union of `nix-store --query <query>` over `paths`
+fn query_all(query: &str, paths: &[PathBuf]) -> anyhow::Result<BTreeSet<PathBuf>> {
+    let output = nix_store_batched(&["--query", query], paths)?;
+    Ok(lines_to_paths(&output).into_iter().collect())
+}
+
+/// Run `nix-store` with `args` followed by `paths`, in as many invocations as needed to
+/// stay below the argument size limit, outputs are concatenated
+fn nix_store_batched(args: &[&str], paths: &[PathBuf]) -> anyhow::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    for chunk in chunks(paths) {
+        output.extend(nix_store(
+            args.iter()
+                .map(OsStr::new)
+                .chain(chunk.iter().map(|p| p.as_os_str())),
+        )?);
+    }
+    Ok(output)
+}
+
+/// Split `paths` into chunks of at most [`MAX_ARGS_BYTES`] bytes of arguments,
+/// an empty slice gives no chunk
+fn chunks(paths: &[PathBuf]) -> Vec<&[PathBuf]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    let mut size = 0;
+    for (i, path) in paths.iter().enumerate() {
+        let len = path.as_os_str().len() + 1;
+        if size + len > MAX_ARGS_BYTES && i > start {
+            chunks.push(&paths[start..i]);
+            start = i;
+            size = 0;
+        }
+        size += len;
+    }
+    if start < paths.len() {
+        chunks.push(&paths[start..]);
+    }
+    chunks
+}
+
+fn nix_store<I, S>(args: I) -> anyhow::Result<Vec<u8>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut command = Command::new("nix-store");
+    command.args(args);
+    Ok(run(command)?.stdout)
+}
+
+fn run(mut command: Command) -> anyhow::Result<Output> {
+    log::trace!("running {command:?}");
+    let output = command
+        .output()
+        .with_context(|| format!("failed to run {command:?}"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{command:?} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_names() {
+        assert_eq!(package_name(b"hello-2.12.1"), b"hello");
+        assert_eq!(package_name(b"python3-3.11.4"), b"python3");
+        assert_eq!(
+            package_name(b"nix-output-monitor-2.1.1"),
+            b"nix-output-monitor"
+        );
+        assert_eq!(package_name(b"gtk+3-3.24.38"), b"gtk+3");
+        assert_eq!(package_name(b"source"), b"source");
+        assert_eq!(package_name(b"hello-"), b"hello-");
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    ffi::CString,
+    fs,
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+use crate::{
+    options::{HookShell, ShellHookOptions, TouchOptions},
+    scan_directories, RunContext,
+};
+
+pub fn touch(options: TouchOptions) -> anyhow::Result<()> {
+    let project = match &options.path {
+        Some(p) => p.clone(),
+        None => std::env::current_dir().context("failed to get current directory")?,
+    };
+    let project = fs::canonicalize(&project)
+        .with_context(|| format!("failed to canonicalize project directory {project:?}"))?;
+    let directories = RunContext::directories(&options.directory)?;
+    scan_directories(&directories, None, |link_path| {
+        let target = match fs::read_link(&link_path) {
+            Ok(t) => t,
+            Err(e) => {
+                log::debug!("failed to read symbolic link {link_path:?}: {e}");
+                return Ok(());
+            }
+        };
+        if !in_project(&target, &project) {
+            return Ok(());
+        }
+        match touch_symlink(&target) {
+            Ok(()) => log::info!("touched {target:?}"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::debug!("target of {link_path:?} not found, skip")
+            }
+            Err(e) => log::warn!("failed to touch {target:?}: {e}"),
+        }
+        Ok(())
+    })
+}
+
+/// Whether the directory of `target` is the canonical `project` or one of its ancestors,
+/// a root directly in `/` or the home directory is never part of a project
+fn in_project(target: &Path, project: &Path) -> bool {
+    let Some(dir) = target.parent() else {
+        return false;
+    };
+    let dir = fs::canonicalize(dir).unwrap_or_else(|_| dir.to_owned());
+    if dir.parent().is_none() || home_dir().is_some_and(|home| home == dir) {
+        return false;
+    }
+    project.starts_with(&dir)
+}
+
+fn home_dir() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").filter(|h| !h.is_empty())?;
+    fs::canonicalize(&home).ok()
+}
+
+/// Set the modified time of a symbolic link itself to now
+pub fn touch_symlink<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    set_symlink_mtime_raw(
+        path.as_ref(),
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_NOW,
+        },
+    )
+}
+
+/// Set the modified time of a symbolic link itself
+pub fn set_symlink_mtime<P: AsRef<Path>>(path: P, mtime: SystemTime) -> io::Result<()> {
+    let since_epoch = mtime
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    set_symlink_mtime_raw(
+        path.as_ref(),
+        libc::timespec {
+            tv_sec: since_epoch.as_secs() as libc::time_t,
+            tv_nsec: since_epoch.subsec_nanos() as libc::c_long,
+        },
+    )
+}
+
+fn set_symlink_mtime_raw(path: &Path, mtime: libc::timespec) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec {
+            tv_sec: 0,
+            tv_nsec: libc::UTIME_OMIT,
+        },
+        mtime,
+    ];
+    // SAFETY: `path` is a valid NUL terminated string and `times` holds two timespecs
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+pub fn shell_hook(options: ShellHookOptions) -> anyhow::Result<()> {
+    let exe = std::env::current_exe()
+        .map(PathBuf::into_os_string)
+        .unwrap_or_else(|_| "angrr".into());
+    let exe = quote(options.shell, &exe.to_string_lossy());
+    let hook = match options.shell {
+        HookShell::Bash => format!(
+            r#"_angrr_hook() {{
+  if [[ "$PWD" != "${{_angrr_last_pwd:-}}" ]]; then
+    _angrr_last_pwd="$PWD"
+    {exe} touch >/dev/null 2>&1
+  fi
+}}
+if [[ ";${{PROMPT_COMMAND[*]:-}};" != *";_angrr_hook;"* ]]; then
+  PROMPT_COMMAND="_angrr_hook${{PROMPT_COMMAND:+;$PROMPT_COMMAND}}"
+fi
+"#
+        ),
+        HookShell::Zsh => format!(
+            r#"_angrr_hook() {{
+  {exe} touch >/dev/null 2>&1
+}}
+autoload -Uz add-zsh-hook
+add-zsh-hook chpwd _angrr_hook
+_angrr_hook
+"#
+        ),
+        HookShell::Fish => format!(
+            r#"function __angrr_hook --on-variable PWD
+  {exe} touch >/dev/null 2>&1
+end
+__angrr_hook
+"#
+        ),
+    };
+    io::stdout()
+        .write_all(hook.as_bytes())
+        .context("failed to write shell hook")
+}
+
+/// Quote `s` as a single word for `shell`
+fn quote(shell: HookShell, s: &str) -> String {
+    match shell {
+        HookShell::Bash | HookShell::Zsh => format!("'{}'", s.replace('\'', r"'\''")),
+        HookShell::Fish => format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'")),
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    ffi::CString,
+    fs::Metadata,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::{ffi::OsStrExt, fs::MetadataExt},
+    },
+    path::{Path, PathBuf},
+};
+
+/// What a file looked like when it was scanned
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// device and inode number, not checked if unknown
+    id: Option<(u64, u64)>,
+    /// content of the symbolic link, `None` if it is not a symbolic link
+    content: Option<PathBuf>,
+}
+
+impl Snapshot {
+    pub fn new(metadata: &Metadata, content: Option<PathBuf>) -> Self {
+        Self {
+            id: Some((metadata.dev(), metadata.ino())),
+            content,
+        }
+    }
+
+    /// A symbolic link only known by its content
+    pub fn link(content: PathBuf) -> Self {
+        Self {
+            id: None,
+            content: Some(content),
+        }
+    }
+}
+
+/// Remove `path` only if it is still what `snapshot` recorded, returns whether it was removed
+///
+/// The parent directory is held open, and the file is checked and removed relative to it,
+/// so a link replaced after the scan, e.g. by a rebuilt direnv profile, is never removed.
+pub fn unlink_if_unchanged(path: &Path, snapshot: &Snapshot) -> io::Result<bool> {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let dir = open_directory(parent)?;
+    let name = CString::new(name.as_bytes())?;
+    if let Some(id) = snapshot.id {
+        // SAFETY: `stat` is plain old data, filled by the call below
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        // SAFETY: `dir` is an open directory, `name` is nul terminated, `stat` is a valid buffer
+        let r = unsafe {
+            libc::fstatat(
+                dir.as_raw_fd(),
+                name.as_ptr(),
+                &mut stat,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if r != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if (stat.st_dev, stat.st_ino) != id {
+            return Ok(false);
+        }
+    }
+    if let Some(content) = &snapshot.content {
+        if read_link_at(&dir, &name)? != content.as_os_str().as_bytes() {
+            return Ok(false);
+        }
+    }
+    // SAFETY: `dir` is an open directory and `name` is nul terminated
+    if unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(true)
+}
+
+fn open_directory(path: &Path) -> io::Result<OwnedFd> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `path` is nul terminated
+    let fd = unsafe {
+        libc::open(
+            path.as_ptr(),
+            libc::O_DIRECTORY | libc::O_RDONLY | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` was just opened and is owned by nothing else
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+fn read_link_at(dir: &OwnedFd, name: &CString) -> io::Result<Vec<u8>> {
+    let mut buffer = vec![0u8; libc::PATH_MAX as usize];
+    // SAFETY: `dir` is an open directory, `name` is nul terminated
+    // and `buffer` is writable for its whole length
+    let len = unsafe {
+        libc::readlinkat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            buffer.as_mut_ptr().cast(),
+            buffer.len(),
+        )
+    };
+    if len < 0 {
+        let error = io::Error::last_os_error();
+        // not a symbolic link any more
+        return match error.raw_os_error() {
+            Some(libc::EINVAL) => Ok(Vec::new()),
+            _ => Err(error),
+        };
+    }
+    buffer.truncate(len as usize);
+    Ok(buffer)
+}